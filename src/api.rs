@@ -0,0 +1,237 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Manual instrumentation api exposed to php code.
+//!
+//! ```php
+//! $traceId = skywalking_trace_id();
+//! $segmentId = skywalking_segment_id();
+//! $spanId = skywalking_span_id();
+//!
+//! $result = \SkyWalking\Span::local('Order/checkout', function () {
+//!     \SkyWalking\Span::addTag('order.id', '10086');
+//!     \SkyWalking\Span::addLog('stage', 'paid');
+//!     return checkout();
+//! });
+//!
+//! \SkyWalking\Correlation::put('tenant', 'acme');
+//! $tenant = \SkyWalking\Correlation::get('tenant');
+//! ```
+//!
+//! The functions never throw, errors (such as the request isn't traced) are
+//! only logged, and the trace id functions return `null`.
+
+use crate::{
+    context::RequestContext,
+    plugin::{create_local_span, mark_span_with_exception},
+    redact::{RedactedLog, RedactedTag},
+    util::z_val_to_string,
+};
+use anyhow::Context;
+use phper::{
+    classes::{StatefulClass, Visibility},
+    functions::{call, Argument},
+    modules::Module,
+    values::ZVal,
+};
+use skywalking::context::span::Span;
+use std::cell::RefCell;
+use tracing::error;
+
+const SPAN_CLASS_NAME: &str = "SkyWalking\\Span";
+
+const CORRELATION_CLASS_NAME: &str = "SkyWalking\\Correlation";
+
+thread_local! {
+    /// Stack of the spans created by `SkyWalking\Span::local`, the tags and
+    /// logs are added to the innermost one.
+    static LOCAL_SPANS: RefCell<Vec<Span>> = Default::default();
+}
+
+/// Register the api functions and classes.
+pub fn register_api(module: &mut Module) {
+    module.add_function("skywalking_trace_id", |_| trace_id(), vec![]);
+    module.add_function("skywalking_segment_id", |_| segment_id(), vec![]);
+    module.add_function("skywalking_span_id", |_| span_id(), vec![]);
+
+    let mut class = StatefulClass::<()>::new(SPAN_CLASS_NAME);
+    class.add_static_method(
+        "local",
+        Visibility::Public,
+        |arguments| span_local(arguments),
+        vec![Argument::by_val("operation_name"), Argument::by_val("callback")],
+    );
+    class.add_static_method(
+        "addTag",
+        Visibility::Public,
+        |arguments| span_add_tag(arguments),
+        vec![Argument::by_val("key"), Argument::by_val("value")],
+    );
+    class.add_static_method(
+        "addLog",
+        Visibility::Public,
+        |arguments| span_add_log(arguments),
+        vec![Argument::by_val("key"), Argument::by_val("value")],
+    );
+    module.add_class(class);
+
+    let mut class = StatefulClass::<()>::new(CORRELATION_CLASS_NAME);
+    class.add_static_method(
+        "get",
+        Visibility::Public,
+        |arguments| correlation_get(arguments),
+        vec![Argument::by_val("key")],
+    );
+    class.add_static_method(
+        "put",
+        Visibility::Public,
+        |arguments| correlation_put(arguments),
+        vec![Argument::by_val("key"), Argument::by_val("value")],
+    );
+    module.add_class(class);
+}
+
+/// Finish the local spans left by `SkyWalking\Span::local`, such as the
+/// callback calls `exit()`, must be called before the request context removed.
+pub fn finish_local_spans() {
+    let mut spans = LOCAL_SPANS.with(|spans| spans.take());
+    while let Some(span) = spans.pop() {
+        drop(span);
+    }
+}
+
+fn trace_id() -> ZVal {
+    RequestContext::with_global(None, |ctx| ctx.tracing_context.trace_id().to_owned())
+        .map(ZVal::from)
+        .unwrap_or_else(|| ZVal::from(()))
+}
+
+fn segment_id() -> ZVal {
+    RequestContext::with_global(None, |ctx| ctx.tracing_context.trace_segment_id().to_owned())
+        .map(ZVal::from)
+        .unwrap_or_else(|| ZVal::from(()))
+}
+
+/// The id of the active span, in the current segment.
+fn span_id() -> ZVal {
+    RequestContext::with_global(None, |ctx| ctx.tracing_context.peek_active_span_id())
+        .flatten()
+        .map(|span_id| ZVal::from(span_id as i64))
+        .unwrap_or_else(|| ZVal::from(()))
+}
+
+/// `SkyWalking\Span::local(string $operation_name, callable $callback)`, call
+/// the callback in a local span, and return the result of callback.
+///
+/// The callback is still called if the local span can't be created.
+fn span_local(arguments: &mut [ZVal]) -> ZVal {
+    let span = z_val_to_string(&arguments[0])
+        .context("operation name isn't string")
+        .and_then(|operation_name| create_local_span(&operation_name));
+    let span = match span {
+        Ok(span) => {
+            LOCAL_SPANS.with(|spans| spans.borrow_mut().push(span));
+            true
+        }
+        Err(err) => {
+            error!(?err, "create local span failed");
+            false
+        }
+    };
+
+    let result = call("call_user_func", &mut [arguments[1].clone()]);
+
+    if span {
+        if let Some(mut span) = LOCAL_SPANS.with(|spans| spans.borrow_mut().pop()) {
+            if let Err(err) = mark_span_with_exception(&mut span) {
+                error!(?err, "mark local span failed");
+            }
+        }
+    }
+
+    match result {
+        Ok(result) => result,
+        Err(err) => {
+            error!(?err, "call local span callback failed");
+            ZVal::from(())
+        }
+    }
+}
+
+/// `SkyWalking\Span::addTag(string $key, string $value)`, add tag to the
+/// innermost local span, or the entry span if not in local span.
+fn span_add_tag(arguments: &mut [ZVal]) {
+    let result = with_current_span(arguments, |span, key, value| {
+        span.add_redacted_tag(key, value)
+    });
+    if let Err(err) = result {
+        error!(?err, "add tag failed");
+    }
+}
+
+/// `SkyWalking\Span::addLog(string $key, string $value)`, add log to the
+/// innermost local span, or the entry span if not in local span.
+fn span_add_log(arguments: &mut [ZVal]) {
+    let result = with_current_span(arguments, |span, key, value| {
+        span.add_redacted_log([(key, value)])
+    });
+    if let Err(err) = result {
+        error!(?err, "add log failed");
+    }
+}
+
+fn with_current_span(
+    arguments: &mut [ZVal], f: impl FnOnce(&mut Span, &str, &str),
+) -> anyhow::Result<()> {
+    let key = z_val_to_string(&arguments[0]).context("key isn't string")?;
+    let value = z_val_to_string(&arguments[1]).context("value isn't string")?;
+
+    let handled = LOCAL_SPANS.with(|spans| match spans.borrow_mut().last_mut() {
+        Some(span) => {
+            f(span, &key, &value);
+            None
+        }
+        None => Some(f),
+    });
+
+    match handled {
+        None => Ok(()),
+        Some(f) => RequestContext::with_global(None, |ctx| f(&mut ctx.entry_span, &key, &value))
+            .context("global request context not exists"),
+    }
+}
+
+/// `SkyWalking\Correlation::get(string $key): ?string`, get the correlation
+/// value continued from the entry or put by the request.
+fn correlation_get(arguments: &mut [ZVal]) -> ZVal {
+    let value = z_val_to_string(&arguments[0]).and_then(|key| {
+        RequestContext::with_global(None, |ctx| ctx.correlation.get(&key).map(ToOwned::to_owned))
+            .flatten()
+    });
+    value.map(ZVal::from).unwrap_or_else(|| ZVal::from(()))
+}
+
+/// `SkyWalking\Correlation::put(string $key, string $value): bool`, put the
+/// correlation value injected into the outgoing propagation points, return
+/// false if exceeds the limits or the request isn't traced.
+fn correlation_put(arguments: &mut [ZVal]) -> bool {
+    let key = z_val_to_string(&arguments[0]);
+    let value = z_val_to_string(&arguments[1]);
+    match (key, value) {
+        (Some(key), Some(value)) => {
+            RequestContext::with_global(None, |ctx| ctx.correlation.put(key, value))
+                .unwrap_or_default()
+        }
+        _ => {
+            error!("correlation key or value isn't string");
+            false
+        }
+    }
+}
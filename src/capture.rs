@@ -0,0 +1,120 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Capture the allowed http headers and parameters as tags, the values are
+//! capped and redacted.
+//!
+//! - `http.headers.{name}`: The request headers of entry span and cURL.
+//! - `http.response.headers.{name}`: The response headers of cURL.
+//! - `http.params`: The `$_GET` and `$_POST` parameters of entry span.
+
+use crate::{
+    redact::{is_redacted_param, redact_header, split_names, RedactedTag, MASK},
+    util::z_val_to_string,
+    SKYWALKING_AGENT_CURL_CAPTURE_REQUEST_HEADERS, SKYWALKING_AGENT_CURL_CAPTURE_RESPONSE_HEADERS,
+    SKYWALKING_AGENT_HTTP_CAPTURE_HEADERS, SKYWALKING_AGENT_HTTP_CAPTURE_MAX_LENGTH,
+    SKYWALKING_AGENT_HTTP_CAPTURE_PARAMS,
+};
+use once_cell::sync::Lazy;
+use phper::{arrays::ZArr, ini::Ini};
+use skywalking::context::span::Span;
+
+pub static HTTP_HEADERS: Lazy<Vec<String>> =
+    Lazy::new(|| split_names(SKYWALKING_AGENT_HTTP_CAPTURE_HEADERS));
+
+/// The parameter names are case sensitive.
+pub static HTTP_PARAMS: Lazy<Vec<String>> = Lazy::new(|| {
+    Ini::get::<String>(SKYWALKING_AGENT_HTTP_CAPTURE_PARAMS)
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .collect()
+});
+
+pub static CURL_REQUEST_HEADERS: Lazy<Vec<String>> =
+    Lazy::new(|| split_names(SKYWALKING_AGENT_CURL_CAPTURE_REQUEST_HEADERS));
+
+pub static CURL_RESPONSE_HEADERS: Lazy<Vec<String>> =
+    Lazy::new(|| split_names(SKYWALKING_AGENT_CURL_CAPTURE_RESPONSE_HEADERS));
+
+static MAX_LENGTH: Lazy<usize> = Lazy::new(|| {
+    let max_length = Ini::get::<i64>(SKYWALKING_AGENT_HTTP_CAPTURE_MAX_LENGTH).unwrap_or(0);
+    if max_length <= 0 {
+        usize::MAX
+    } else {
+        max_length as usize
+    }
+});
+
+fn cap(value: &str) -> &str {
+    let max_length = *MAX_LENGTH;
+    if value.len() <= max_length {
+        return value;
+    }
+    let mut end = max_length;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Add the tags `http.headers.{name}` of the allowed request headers, got by
+/// the lowercase name.
+pub fn add_request_header_tags(span: &mut Span, get_header: impl Fn(&str) -> Option<String>) {
+    for name in &*HTTP_HEADERS {
+        if let Some(value) = get_header(name) {
+            add_header_tag(span, "http.headers.", name, &value);
+        }
+    }
+}
+
+/// Add the tags of the allowed headers in the lines like `Name: value`, with
+/// the tag prefix like `http.headers.`.
+pub fn add_header_line_tags<'a>(
+    span: &mut Span, prefix: &str, allowed: &[String], lines: impl IntoIterator<Item = &'a str>,
+) {
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        if allowed.contains(&name) {
+            add_header_tag(span, prefix, &name, value);
+        }
+    }
+}
+
+fn add_header_tag(span: &mut Span, prefix: &str, name: &str, value: &str) {
+    let value = redact_header(name, value);
+    span.add_redacted_tag(&format!("{prefix}{name}"), cap(&value));
+}
+
+/// Add the tag `http.params` of the allowed parameters, like `a=1&b=2`, the
+/// parameters of `$_POST` are after `$_GET`.
+pub fn add_params_tag(span: &mut Span, params: &[Option<&ZArr>]) {
+    if HTTP_PARAMS.is_empty() {
+        return;
+    }
+
+    let mut pairs = Vec::new();
+    for params in params.iter().flatten() {
+        for name in &*HTTP_PARAMS {
+            if let Some(value) = params.get(&**name).and_then(z_val_to_string) {
+                let value = if is_redacted_param(name) { MASK } else { cap(&value) };
+                pairs.push(format!("{name}={value}"));
+            }
+        }
+    }
+
+    if !pairs.is_empty() {
+        span.add_redacted_tag("http.params", &pairs.join("&"));
+    }
+}
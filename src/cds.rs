@@ -0,0 +1,406 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Dynamic configuration by `ConfigurationDiscoveryService/fetchConfigurations`
+//! (CDS).
+//!
+//! The worker fetches the configuration of the service periodically, and
+//! writes the changed one (by the `UUID`) into the shared memory created in
+//! module init. The php processes read the shared memory in request init, and
+//! apply the configuration only if the `UUID` is different from the applied
+//! one.
+//!
+//! The supported keys:
+//!
+//! - `agent.sample_n_per_3_secs`: Max count of the new traces per 3 seconds,
+//!   shared by all processes, the propagated traces are always kept.
+//! - `agent.trace.ignore_path`: Paths not traced, separated by comma, the
+//!   patterns `**`, `*` and `?` are supported, like `/health/**`.
+//! - `agent.ignore_suffix`: Path suffixes not traced, separated by comma, like
+//!   `.jpg,.css`.
+//! - `agent.span_limit_per_segment`: Max count of the spans per request.
+//! - `agent.plugins.disabled`: Plugins not hooked, separated by comma, like
+//!   `curl,pdo`.
+
+use crate::{
+    module::SERVICE_NAME, SKYWALKING_AGENT_CDS_ENABLE, SKYWALKING_AGENT_CDS_FETCH_INTERVAL,
+};
+use anyhow::bail;
+use ipc_channel::ipc::IpcSharedMemory;
+use once_cell::sync::Lazy;
+use phper::ini::Ini;
+use skywalking::skywalking_proto::v3::{
+    configuration_discovery_service_client::ConfigurationDiscoveryServiceClient, Commands,
+    ConfigurationSyncRequest,
+};
+use std::{
+    cell::{Cell, RefCell},
+    mem::size_of,
+    sync::atomic::{fence, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tonic::transport::Channel;
+use tracing::{debug, info, warn};
+
+/// Max bytes of the configuration text in the shared memory.
+const CAPACITY: usize = 64 * 1024;
+
+const COMMAND_NAME: &str = "ConfigurationDiscoveryCommand";
+
+const UUID_KEY: &str = "UUID";
+
+const SERIAL_NUMBER_KEY: &str = "SerialNumber";
+
+pub static CDS_ENABLE: Lazy<bool> =
+    Lazy::new(|| Ini::get::<bool>(SKYWALKING_AGENT_CDS_ENABLE).unwrap_or_default());
+
+static FETCH_INTERVAL: Lazy<Duration> = Lazy::new(|| {
+    let interval = Ini::get::<i64>(SKYWALKING_AGENT_CDS_FETCH_INTERVAL).unwrap_or(0);
+    Duration::from_secs(if interval <= 0 { 20 } else { interval as u64 })
+});
+
+/// The configuration in the shared memory, the text is the lines like
+/// `key=value`, guarded by the sequence (odd while writing), there is only
+/// one writer (the worker).
+#[repr(C)]
+struct SharedConfig {
+    seq: AtomicU64,
+    len: AtomicUsize,
+    data: [AtomicU8; CAPACITY],
+    /// The 3 seconds window of sampling, and the count of traces in it.
+    sample_window: AtomicU64,
+    sample_count: AtomicU64,
+}
+
+/// Create the shared memory of configuration, should be called in module init,
+/// before the worker and php processes forked.
+pub fn init_shared_config() {
+    if *CDS_ENABLE {
+        get_shared_config();
+    }
+}
+
+fn get_shared_config() -> &'static SharedConfig {
+    static SHARED_CONFIG: Lazy<IpcSharedMemory> =
+        Lazy::new(|| IpcSharedMemory::from_byte(0, size_of::<SharedConfig>()));
+    let ptr = SHARED_CONFIG.as_ptr() as *const SharedConfig;
+    unsafe { ptr.as_ref().unwrap() }
+}
+
+impl SharedConfig {
+    fn write(&self, text: &str) -> anyhow::Result<()> {
+        let bytes = text.as_bytes();
+        if bytes.len() > CAPACITY {
+            bail!("configuration is too large, length: {}", bytes.len());
+        }
+
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        for (dst, src) in self.data.iter().zip(bytes) {
+            dst.store(*src, Ordering::Relaxed);
+        }
+        self.len.store(bytes.len(), Ordering::Relaxed);
+        self.seq.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Read the text if the sequence is changed from `last_seq`, return the
+    /// new sequence and the text, or `None` if it is being written, so it will
+    /// be read in the next request.
+    fn read_changed(&self, last_seq: u64) -> Option<(u64, String)> {
+        let seq = self.seq.load(Ordering::SeqCst);
+        if seq == last_seq || seq % 2 == 1 {
+            return None;
+        }
+
+        let len = self.len.load(Ordering::Relaxed).min(CAPACITY);
+        let bytes = self.data[..len]
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+
+        // The relaxed loads of data can't be reordered after the sequence check.
+        fence(Ordering::Acquire);
+        if self.seq.load(Ordering::SeqCst) != seq {
+            return None;
+        }
+        Some((seq, String::from_utf8_lossy(&bytes).into_owned()))
+    }
+}
+
+/// The configuration applied in the php process.
+#[derive(Default)]
+struct DynamicConfig {
+    uuid: String,
+    sample_n_per_3_secs: Option<u64>,
+    ignore_paths: Vec<String>,
+    ignore_suffixes: Vec<String>,
+    span_limit: Option<usize>,
+    disabled_plugins: Vec<String>,
+}
+
+impl DynamicConfig {
+    fn parse(text: &str) -> Self {
+        let mut config = Self::default();
+        for (key, value) in text.lines().filter_map(|line| line.split_once('=')) {
+            let value = value.trim();
+            match key {
+                UUID_KEY => config.uuid = value.to_owned(),
+                "agent.sample_n_per_3_secs" => config.sample_n_per_3_secs = parse_limit(key, value),
+                "agent.trace.ignore_path" => config.ignore_paths = split_list(value),
+                "agent.ignore_suffix" => config.ignore_suffixes = split_list(value),
+                "agent.span_limit_per_segment" => {
+                    config.span_limit = parse_limit(key, value).map(|limit| limit as usize)
+                }
+                "agent.plugins.disabled" => {
+                    config.disabled_plugins = split_list(&value.to_ascii_lowercase())
+                }
+                _ => debug!(key, "unsupported dynamic configuration"),
+            }
+        }
+        config
+    }
+}
+
+/// Parse the limit, the non positive value means unlimited.
+fn parse_limit(key: &str, value: &str) -> Option<u64> {
+    match value.parse::<i64>() {
+        Ok(limit) if limit > 0 => Some(limit as u64),
+        Ok(_) => None,
+        Err(_) => {
+            warn!(key, value, "invalid dynamic configuration");
+            None
+        }
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+thread_local! {
+    static LAST_SEQ: Cell<u64> = Cell::new(0);
+    static CONFIG: RefCell<DynamicConfig> = Default::default();
+}
+
+fn with_config<T>(f: impl FnOnce(&DynamicConfig) -> T) -> T {
+    CONFIG.with(|config| f(&config.borrow()))
+}
+
+/// Reload the configuration from the shared memory if changed, should be
+/// called in request init.
+pub fn refresh() {
+    if !*CDS_ENABLE {
+        return;
+    }
+
+    let last_seq = LAST_SEQ.with(Cell::get);
+    let (seq, text) = match get_shared_config().read_changed(last_seq) {
+        Some(changed) => changed,
+        None => return,
+    };
+    LAST_SEQ.with(|last_seq| last_seq.set(seq));
+
+    let config = DynamicConfig::parse(&text);
+    CONFIG.with(|current| {
+        if current.borrow().uuid == config.uuid {
+            return;
+        }
+        info!(uuid = &*config.uuid, "Dynamic configuration applied");
+        *current.borrow_mut() = config;
+    });
+}
+
+/// Get the `UUID` of the applied configuration, `None` if not received.
+pub fn applied_uuid() -> Option<String> {
+    with_config(|config| Some(config.uuid.clone()).filter(|uuid| !uuid.is_empty()))
+}
+
+/// Whether the request path is ignored by `agent.trace.ignore_path` or
+/// `agent.ignore_suffix`.
+pub fn is_ignored_path(path: &str) -> bool {
+    with_config(|config| {
+        config
+            .ignore_suffixes
+            .iter()
+            .any(|suffix| path.ends_with(&**suffix))
+            || config
+                .ignore_paths
+                .iter()
+                .any(|pattern| match_path(pattern.as_bytes(), path.as_bytes()))
+    })
+}
+
+/// Match the path with the pattern, `**` matches any characters, `*` matches
+/// any characters except `/`, and `?` matches one character except `/`.
+fn match_path(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| match_path(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment_len = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+            (0..=segment_len).any(|i| match_path(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => matches!(path, [c, path @ ..] if *c != b'/' && match_path(rest, path)),
+        [c, rest @ ..] => matches!(path, [p, path @ ..] if p == c && match_path(rest, path)),
+    }
+}
+
+/// Take a sample of the new trace by `agent.sample_n_per_3_secs`, the count
+/// is shared by all processes.
+pub fn try_sample() -> bool {
+    let n = match with_config(|config| config.sample_n_per_3_secs) {
+        Some(n) => n,
+        None => return true,
+    };
+
+    let shared = get_shared_config();
+    let window = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / 3)
+        .unwrap_or_default();
+    let current = shared.sample_window.load(Ordering::SeqCst);
+    if current != window
+        && shared
+            .sample_window
+            .compare_exchange(current, window, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    {
+        shared.sample_count.store(0, Ordering::SeqCst);
+    }
+    shared.sample_count.fetch_add(1, Ordering::SeqCst) < n
+}
+
+/// Max count of the spans per request by `agent.span_limit_per_segment`.
+pub fn span_limit() -> Option<usize> {
+    with_config(|config| config.span_limit)
+}
+
+/// Whether the plugin is disabled by `agent.plugins.disabled`.
+pub fn is_plugin_disabled(name: &str) -> bool {
+    with_config(|config| config.disabled_plugins.iter().any(|disabled| disabled == name))
+}
+
+/// Fetch the configuration of the service periodically in worker, and write
+/// it into the shared memory if the `UUID` is changed.
+#[tracing::instrument(skip_all)]
+pub async fn fetch_configurations(channel: Channel) {
+    let mut client = ConfigurationDiscoveryServiceClient::new(channel);
+    let mut uuid = String::new();
+
+    let mut ticker = interval(*FETCH_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        // Only one configuration is shared by the processes, so the pools with
+        // their own service names share the one of the master process.
+        let request = ConfigurationSyncRequest {
+            service: SERVICE_NAME.clone(),
+            uuid: uuid.clone(),
+        };
+        let commands = match client.fetch_configurations(request).await {
+            Ok(commands) => commands.into_inner(),
+            Err(err) => {
+                warn!(?err, "Fetch configurations failed");
+                continue;
+            }
+        };
+
+        if let Some((new_uuid, text)) = parse_commands(commands) {
+            if new_uuid == uuid {
+                continue;
+            }
+            match get_shared_config().write(&text) {
+                Ok(_) => {
+                    info!(uuid = &*new_uuid, "Dynamic configuration changed");
+                    uuid = new_uuid;
+                }
+                Err(err) => warn!(?err, "Store configurations failed"),
+            }
+        }
+    }
+}
+
+/// Get the `UUID` and the configuration text of the last discovery command.
+fn parse_commands(commands: Commands) -> Option<(String, String)> {
+    let command = commands
+        .commands
+        .into_iter()
+        .filter(|command| command.command == COMMAND_NAME)
+        .last()?;
+
+    let mut uuid = None;
+    let mut text = String::new();
+    for arg in command.args {
+        match &*arg.key {
+            UUID_KEY => uuid = Some(arg.value.clone()),
+            SERIAL_NUMBER_KEY => continue,
+            _ => {}
+        }
+        text.push_str(&arg.key);
+        text.push('=');
+        text.push_str(&arg.value.replace(['\r', '\n'], " "));
+        text.push('\n');
+    }
+
+    uuid.map(|uuid| (uuid, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        match_path(pattern.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn test_match_path_literal() {
+        assert!(matches("/health", "/health"));
+        assert!(!matches("/health", "/healthz"));
+        assert!(!matches("/health", "/"));
+        assert!(matches("", ""));
+    }
+
+    #[test]
+    fn test_match_path_single_star() {
+        assert!(matches("/api/*", "/api/users"));
+        assert!(matches("/api/*", "/api/"));
+        assert!(!matches("/api/*", "/api/users/1"));
+        assert!(matches("/api/*/detail", "/api/users/detail"));
+        assert!(!matches("/api/*/detail", "/api/users/1/detail"));
+        assert!(matches("/static/*.js", "/static/app.js"));
+    }
+
+    #[test]
+    fn test_match_path_double_star() {
+        assert!(matches("/api/**", "/api/users/1"));
+        assert!(matches("/api/**", "/api/"));
+        assert!(matches("/**/detail", "/api/users/1/detail"));
+        assert!(!matches("/**/detail", "/api/users/1/details"));
+        assert!(matches("**", "/any/path"));
+    }
+
+    #[test]
+    fn test_match_path_question_mark() {
+        assert!(matches("/user/?", "/user/1"));
+        assert!(!matches("/user/?", "/user/12"));
+        assert!(!matches("/user/?", "/user/"));
+        assert!(!matches("/user?1", "/user/1"));
+    }
+}
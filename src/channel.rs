@@ -8,30 +8,28 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use crate::SKYWALKING_AGENT_MAX_MESSAGE_LENGTH;
+use crate::{meter::RuntimeMetrics, SKYWALKING_AGENT_MAX_MESSAGE_LENGTH};
 use anyhow::{anyhow, bail, Context};
-use ipc_channel::ipc::{self, IpcReceiver, IpcSender, IpcSharedMemory, TryRecvError};
+use ipc_channel::ipc::{self, IpcReceiver, IpcSender, IpcSharedMemory};
 use once_cell::sync::{Lazy, OnceCell};
 use phper::ini::Ini;
+use serde::{Deserialize, Serialize};
 use skywalking::{
     context::tracer::{SegmentReceiver, SegmentSender},
-    skywalking_proto::v3::SegmentObject,
+    skywalking_proto::v3::{LogData, SegmentObject},
 };
 use std::{
     cell::RefCell,
     error::Error,
-    intrinsics::transmute,
     mem::size_of,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Mutex,
     },
 };
-use tokio::task;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
 use tonic::async_trait;
-use tracing::{debug, info};
-
-const MAX_COUNT: usize = 100;
+use tracing::{debug, error, info};
 
 pub static MAX_LENGTH: Lazy<usize> = Lazy::new(|| {
     let mut max_length = Ini::get::<i64>(SKYWALKING_AGENT_MAX_MESSAGE_LENGTH).unwrap_or(0) as usize;
@@ -41,14 +39,52 @@ pub static MAX_LENGTH: Lazy<usize> = Lazy::new(|| {
     max_length
 });
 
+/// The message sent from the php processes to the worker.
+#[derive(Serialize, Deserialize)]
+pub enum Message {
+    Segment(SegmentObject),
+    Log(LogData),
+    Meter(RuntimeMetrics),
+}
+
+impl Message {
+    fn kind(&self) -> MessageKind {
+        match self {
+            Message::Segment(_) => MessageKind::Segment,
+            Message::Log(_) => MessageKind::Log,
+            Message::Meter(_) => MessageKind::Meter,
+        }
+    }
+}
+
+/// The kinds of messages, each kind has its own budget of the messages not
+/// reported yet, so the logs can't starve the segments.
+#[derive(Debug, Clone, Copy)]
+pub enum MessageKind {
+    Segment = 0,
+    Log = 1,
+    Meter = 2,
+}
+
+impl MessageKind {
+    /// Max count of the messages sent but not reported (or dropped) yet.
+    pub const fn max_count(self) -> usize {
+        match self {
+            MessageKind::Segment => 100,
+            MessageKind::Log => 100,
+            MessageKind::Meter => 20,
+        }
+    }
+}
+
 thread_local! {
-    static SENDER: RefCell<Option<IpcSender<SegmentObject>>> = Default::default();
+    static SENDER: RefCell<Option<IpcSender<Message>>> = Default::default();
 }
 
-static RECEIVER: OnceCell<Mutex<IpcReceiver<SegmentObject>>> = OnceCell::new();
+static RECEIVER: OnceCell<Mutex<IpcReceiver<Message>>> = OnceCell::new();
 
 pub fn init_channel() -> anyhow::Result<()> {
-    get_count()?;
+    get_count(MessageKind::Segment)?;
 
     let max_length = *MAX_LENGTH;
     info!(max_length, "The max length of report body");
@@ -70,96 +106,144 @@ pub fn init_channel() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn get_count() -> anyhow::Result<&'static AtomicUsize> {
-    static COUNT: OnceCell<IpcSharedMemory> = OnceCell::new();
-    let count = COUNT.get_or_init(|| {
-        let count: [u8; size_of::<AtomicUsize>()] = unsafe { transmute(AtomicUsize::new(0)) };
-        IpcSharedMemory::from_bytes(&count)
+/// Get the shared count of the messages not reported yet of the kind.
+fn get_count(kind: MessageKind) -> anyhow::Result<&'static AtomicUsize> {
+    static COUNTS: OnceCell<IpcSharedMemory> = OnceCell::new();
+    let counts = COUNTS.get_or_init(|| {
+        // The zeroed bytes are the atomics of zero.
+        IpcSharedMemory::from_byte(0, size_of::<[AtomicUsize; 3]>())
     });
-    let ptr = count.as_ptr() as *const AtomicUsize;
+    let ptr = counts.as_ptr() as *const [AtomicUsize; 3];
     unsafe {
         ptr.as_ref()
+            .map(|counts| &counts[kind as usize])
             .context("Shared memory of message count is null")
     }
 }
 
-fn channel_send(data: SegmentObject) -> anyhow::Result<()> {
+/// Release the budget of the messages, called after the messages reported or
+/// dropped.
+pub fn release_count(kind: MessageKind, count: usize) {
+    match get_count(kind) {
+        Ok(counter) => {
+            counter.fetch_sub(count, Ordering::SeqCst);
+        }
+        Err(err) => error!(?err, ?kind, "Release message count failed"),
+    }
+}
+
+fn channel_send(data: Message) -> anyhow::Result<()> {
     // if data.len() > *MAX_LENGTH {
     //     bail!("Send data is too big");
     // }
 
-    let old_count = get_count()?.fetch_add(1, Ordering::SeqCst);
-    if old_count >= MAX_COUNT {
+    let kind = data.kind();
+    let count = get_count(kind)?;
+    let old_count = count.fetch_add(1, Ordering::SeqCst);
+    if old_count >= kind.max_count() {
+        count.fetch_sub(1, Ordering::SeqCst);
         bail!("Channel is fulled");
     }
-    debug!("Channel remainder count: {}", old_count);
+    debug!(?kind, "Channel remainder count: {}", old_count);
 
-    SENDER.with(|sender| {
+    let result = SENDER.with(|sender| {
         sender
             .borrow_mut()
             .as_ref()
             .context("Channel haven't initialized")
             .and_then(|sender| sender.send(data).context("Channel send failed"))
-    })
+    });
+    if result.is_err() {
+        count.fetch_sub(1, Ordering::SeqCst);
+    }
+    result
 }
 
-fn channel_receive() -> anyhow::Result<SegmentObject> {
+fn channel_receive() -> anyhow::Result<Message> {
     let receiver = RECEIVER
         .get()
         .context("Channel haven't initialized")?
         .lock()
         .map_err(|_| anyhow!("Get lock failed"))?;
 
-    let r = receiver.recv();
-    get_count()?.fetch_sub(1, Ordering::SeqCst);
-    Ok(r?)
+    Ok(receiver.recv()?)
 }
 
-fn channel_try_receive() -> anyhow::Result<Option<SegmentObject>> {
-    let receiver = RECEIVER
-        .get()
-        .context("Channel haven't initialized")?
-        .lock()
-        .map_err(|_| anyhow!("Get lock failed"))?;
+/// Send the log to the worker, by the same channel of segments.
+pub fn send_log(log: LogData) -> anyhow::Result<()> {
+    channel_send(Message::Log(log))
+}
+
+/// Send the runtime metrics to the worker, by the same channel of segments.
+pub fn send_meter(metrics: RuntimeMetrics) -> anyhow::Result<()> {
+    channel_send(Message::Meter(metrics))
+}
 
-    let r = match receiver.try_recv() {
-        Ok(data) => Ok(Some(data)),
-        Err(TryRecvError::Empty) => Ok(None),
-        Err(e) => Err(e.into()),
-    };
-    get_count()?.fetch_sub(1, Ordering::SeqCst);
-    r
+/// Receive the messages in worker, and dispatch them to the segment receiver,
+/// the log reporter and the meter reporter, blocking, should be run in the
+/// blocking thread.
+///
+/// The budgets of the messages are released by the receivers, after they are
+/// reported.
+pub fn dispatch(
+    segment_sender: mpsc::Sender<SegmentObject>, log_sender: mpsc::Sender<LogData>,
+    meter_sender: mpsc::Sender<RuntimeMetrics>,
+) {
+    loop {
+        let message = match channel_receive() {
+            Ok(message) => message,
+            Err(err) => {
+                error!(?err, "Channel receive failed");
+                break;
+            }
+        };
+        let kind = message.kind();
+        let result = match message {
+            Message::Segment(segment) => segment_sender.blocking_send(segment).is_ok(),
+            Message::Log(log) => log_sender.blocking_send(log).is_ok(),
+            Message::Meter(metrics) => meter_sender.blocking_send(metrics).is_ok(),
+        };
+        if !result {
+            release_count(kind, 1);
+            break;
+        }
+    }
 }
 
 pub struct Sender;
 
 impl SegmentSender for Sender {
     fn send(&self, segment: SegmentObject) -> Result<(), Box<dyn Error>> {
-        Ok(channel_send(segment)?)
+        Ok(channel_send(Message::Segment(segment))?)
     }
 }
 
-pub struct Receiver;
+/// The segment receiver of tracer in worker, the segments are dispatched by
+/// [dispatch], the budget is released when the segment is taken by the
+/// reporter.
+pub struct Receiver(AsyncMutex<mpsc::Receiver<SegmentObject>>);
+
+impl Receiver {
+    pub fn new(receiver: mpsc::Receiver<SegmentObject>) -> Self {
+        Self(AsyncMutex::new(receiver))
+    }
+}
 
 #[async_trait]
 impl SegmentReceiver for Receiver {
     async fn recv(&self) -> Result<Option<SegmentObject>, Box<dyn Error + Send>> {
-        match task::spawn_blocking(channel_receive).await {
-            Ok(r) => match r {
-                Ok(segment) => Ok(Some(segment)),
-                Err(e) => Err(e.into()),
-            },
-            Err(e) => Err(Box::new(e)),
+        let segment = self.0.lock().await.recv().await;
+        if segment.is_some() {
+            release_count(MessageKind::Segment, 1);
         }
+        Ok(segment)
     }
 
     async fn try_recv(&self) -> Result<Option<SegmentObject>, Box<dyn Error + Send>> {
-        match task::spawn_blocking(channel_try_receive).await {
-            Ok(r) => match r {
-                Ok(segment) => Ok(segment),
-                Err(e) => Err(e.into()),
-            },
-            Err(e) => Err(Box::new(e)),
+        let segment = self.0.lock().await.try_recv().ok();
+        if segment.is_some() {
+            release_count(MessageKind::Segment, 1);
         }
+        Ok(segment)
     }
 }
@@ -0,0 +1,311 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Resolve the client ip of the request.
+//!
+//! The forwarded headers (`Forwarded`, `X-Forwarded-For` and `X-Real-IP`) are
+//! only honoured when the immediate peer (`REMOTE_ADDR`) is a trusted proxy,
+//! configured by `skywalking_agent.trusted_proxies`. The addresses in the
+//! forwarded chain are walked from right to left, and the first untrusted one
+//! is the client, the walk stops at the unparsable hop (like `unknown` or the
+//! obfuscated `_hidden`), and the last trusted one is the client then.
+//!
+//! The IPv4-mapped IPv6 addresses (like `::ffff:10.0.0.1`) are treated as the
+//! IPv4 addresses.
+
+use crate::SKYWALKING_AGENT_TRUSTED_PROXIES;
+use once_cell::sync::Lazy;
+use phper::ini::Ini;
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::warn;
+
+static TRUSTED_PROXIES: Lazy<Vec<Cidr>> = Lazy::new(|| {
+    Ini::get::<String>(SKYWALKING_AGENT_TRUSTED_PROXIES)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .filter_map(|cidr| {
+            let parsed = Cidr::parse(cidr);
+            if parsed.is_none() {
+                warn!(cidr, "invalid trusted proxy, skipped");
+            }
+            parsed
+        })
+        .collect()
+});
+
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    /// Parse the cidr like `10.0.0.0/8`, or the single address.
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None;
+        }
+        Some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Resolve the client ip by the immediate peer and the forwarded headers, got
+/// by the lowercase name.
+pub fn resolve_client_ip(
+    remote_addr: Option<&str>, get_header: impl Fn(&str) -> Option<String>,
+) -> Option<IpAddr> {
+    resolve(&TRUSTED_PROXIES, remote_addr, get_header)
+}
+
+fn resolve(
+    trusted_proxies: &[Cidr], remote_addr: Option<&str>,
+    get_header: impl Fn(&str) -> Option<String>,
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+
+    let remote_addr = remote_addr.and_then(parse_ip)?;
+    if !is_trusted(&remote_addr) {
+        return Some(remote_addr);
+    }
+
+    let forwarded = get_header("forwarded")
+        .map(|forwarded| parse_forwarded(&forwarded))
+        .filter(|chain| !chain.is_empty())
+        .or_else(|| {
+            get_header("x-forwarded-for")
+                .map(|xff| split_hops(&xff).map(parse_ip).collect::<Vec<_>>())
+                .filter(|chain| !chain.is_empty())
+        });
+
+    match forwarded {
+        Some(chain) => {
+            let mut client = remote_addr;
+            for hop in chain.iter().rev() {
+                match hop {
+                    Some(ip) => {
+                        client = *ip;
+                        if !is_trusted(ip) {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+            Some(client)
+        }
+        None => get_header("x-real-ip")
+            .as_deref()
+            .and_then(parse_ip)
+            .or(Some(remote_addr)),
+    }
+}
+
+/// Get the addresses of `for=` in the header `Forwarded` (RFC 7239), like
+/// `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`, `None` for the hop
+/// without `for=` or not an address.
+fn parse_forwarded(forwarded: &str) -> Vec<Option<IpAddr>> {
+    split_hops(forwarded)
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(parse_ip(value))
+                } else {
+                    None
+                }
+            })?
+        })
+        .collect()
+}
+
+fn split_hops(header: &str) -> impl Iterator<Item = &str> {
+    header.split(',').filter(|hop| !hop.trim().is_empty())
+}
+
+/// Parse the address, which may be quoted, or with port like `1.2.3.4:80` and
+/// `[::1]:80`.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    let ip = if let Ok(ip) = s.parse() {
+        ip
+    } else if let Some(rest) = s.strip_prefix('[') {
+        rest.split_once(']')?.0.parse().ok()?
+    } else {
+        s.rsplit_once(':')?.0.parse().ok()?
+    };
+    Some(to_canonical(ip))
+}
+
+/// Convert the IPv4-mapped IPv6 address to the IPv4 address.
+fn to_canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxies(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs
+            .iter()
+            .map(|cidr| Cidr::parse(cidr).unwrap())
+            .collect()
+    }
+
+    fn headers(
+        headers: &'static [(&'static str, &'static str)],
+    ) -> impl Fn(&str) -> Option<String> {
+        move |name| {
+            headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert_eq!(cidr.addr, ip("10.0.0.0"));
+        assert_eq!(cidr.prefix, 8);
+        assert_eq!(Cidr::parse("10.0.0.1").unwrap().prefix, 32);
+        assert_eq!(Cidr::parse("fd00::/8").unwrap().prefix, 8);
+        assert_eq!(Cidr::parse("::1").unwrap().prefix, 128);
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("10.0.0.0/x").is_none());
+        assert!(Cidr::parse("localhost").is_none());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(!cidr.contains(&ip("11.0.0.1")));
+        assert!(!cidr.contains(&ip("::ffff:10.1.2.3")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("1.2.3.4")));
+        let cidr = Cidr::parse("fd00::/8").unwrap();
+        assert!(cidr.contains(&ip("fd12::1")));
+        assert!(!cidr.contains(&ip("fe80::1")));
+        assert!(!cidr.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_resolve_untrusted_peer() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let get_header = headers(&[("x-forwarded-for", "1.1.1.1")]);
+        assert_eq!(
+            resolve(&trusted, Some("2.2.2.2"), &get_header),
+            Some(ip("2.2.2.2"))
+        );
+        assert_eq!(resolve(&trusted, Some("invalid"), &get_header), None);
+        assert_eq!(resolve(&trusted, None, &get_header), None);
+    }
+
+    #[test]
+    fn test_resolve_forwarded_chain() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let get_header = headers(&[("x-forwarded-for", "3.3.3.3, 1.1.1.1, 10.0.0.2")]);
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), &get_header),
+            Some(ip("1.1.1.1"))
+        );
+
+        let get_header = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), &get_header),
+            Some(ip("10.0.0.3"))
+        );
+
+        let get_header = headers(&[
+            (
+                "forwarded",
+                "for=1.1.1.1;proto=http, for=\"[2001:db8::1]:4711\"",
+            ),
+            ("x-forwarded-for", "3.3.3.3"),
+        ]);
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), &get_header),
+            Some(ip("2001:db8::1"))
+        );
+
+        let get_header = headers(&[("x-real-ip", "1.1.1.1")]);
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), &get_header),
+            Some(ip("1.1.1.1"))
+        );
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), headers(&[])),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_unparsable_hop() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let get_header = headers(&[("x-forwarded-for", "1.1.1.1, unknown, 10.0.0.2")]);
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), &get_header),
+            Some(ip("10.0.0.2"))
+        );
+
+        let get_header = headers(&[("forwarded", "for=1.1.1.1, for=_hidden")]);
+        assert_eq!(
+            resolve(&trusted, Some("10.0.0.1"), &get_header),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_ipv4_mapped() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let get_header = headers(&[("x-forwarded-for", "1.1.1.1")]);
+        assert_eq!(
+            resolve(&trusted, Some("::ffff:10.0.0.1"), &get_header),
+            Some(ip("1.1.1.1"))
+        );
+        assert_eq!(
+            resolve(&trusted, Some("::ffff:2.2.2.2"), &get_header),
+            Some(ip("2.2.2.2"))
+        );
+    }
+}
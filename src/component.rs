@@ -15,3 +15,10 @@
 pub const COMPONENT_PHP_ID: i32 = 8001;
 pub const COMPONENT_PHP_CURL_ID: i32 = 8002;
 pub const COMPONENT_PHP_PDO_ID: i32 = 8003;
+pub const COMPONENT_PHP_YAR_ID: i32 = 8005;
+pub const COMPONENT_POSTGRESQL_ID: i32 = 22;
+pub const COMPONENT_GRPC_ID: i32 = 23;
+pub const COMPONENT_SQLITE_ID: i32 = 31;
+pub const COMPONENT_ELASTICSEARCH_ID: i32 = 47;
+pub const COMPONENT_KAFKA_PRODUCER_ID: i32 = 40;
+pub const COMPONENT_KAFKA_CONSUMER_ID: i32 = 41;
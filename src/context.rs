@@ -1,6 +1,15 @@
+use crate::{
+    cds::span_limit,
+    propagation::{CorrelationContext, ExtensionContext},
+};
 use anyhow::bail;
 use skywalking::context::{span::Span, trace_context::TracingContext};
-use std::{cell::RefCell, mem::take};
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt::{self, Display},
+    mem::take,
+};
 
 // TODO Support cli mode(swoole), so use dashmap to store trace context.
 // static TRACING_CONTEXT_MAP: Lazy<DashMap<u64, (TracingContext, Span)>> =
@@ -12,6 +21,17 @@ thread_local! {
 pub struct RequestContext {
     pub tracing_context: TracingContext,
     pub entry_span: Span,
+    pub correlation: CorrelationContext,
+    pub extension: ExtensionContext,
+    /// The W3C `tracestate` to pass through.
+    pub trace_state: Option<String>,
+    /// The sampled flag injected by the interop propagators.
+    pub sampled: bool,
+    /// Count of the reported logs, limited per request.
+    pub log_count: usize,
+    /// Count of the spans created by plugins, limited by the dynamic
+    /// configuration.
+    pub span_count: usize,
 }
 
 impl RequestContext {
@@ -43,12 +63,75 @@ impl RequestContext {
         }
     }
 
+    /// Rename the entry span with the route matched by framework, like
+    /// `/users/{id}`, the `METHOD:` prefix created in request init is kept.
+    pub fn rename_entry_span_with_route(
+        request_id: Option<u64>, route: &str,
+    ) -> anyhow::Result<()> {
+        match Self::with_global(request_id, |ctx| {
+            ctx.entry_span.with_span_object_mut(|span| {
+                span.operation_name = match span.operation_name.split_once(':') {
+                    Some((method, _)) => format!("{}:{}", method, route),
+                    None => route.to_owned(),
+                };
+            })
+        }) {
+            Some(_) => Ok(()),
+            None => bail!(ContextNotExists),
+        }
+    }
+
+    pub fn try_with_global<T>(
+        request_id: Option<u64>, f: impl FnOnce(&mut RequestContext) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        match Self::with_global(request_id, f) {
+            Some(t) => t,
+            None => bail!(ContextNotExists),
+        }
+    }
+
+    /// Create the span by the global tracing context, the spans per request are
+    /// limited by `agent.span_limit_per_segment` of the dynamic configuration.
     pub fn try_with_global_ctx<T>(
         request_id: Option<u64>, f: impl FnOnce(&mut TracingContext) -> anyhow::Result<T>,
     ) -> anyhow::Result<T> {
-        match Self::with_global(request_id, |ctx| f(&mut ctx.tracing_context)) {
+        match Self::with_global(request_id, |ctx| {
+            if let Some(span_limit) = span_limit() {
+                if ctx.span_count >= span_limit {
+                    bail!(SpanLimitExceeded);
+                }
+            }
+            ctx.span_count += 1;
+            f(&mut ctx.tracing_context)
+        }) {
             Some(t) => t,
-            None => bail!("global tracing context not exists"),
+            None => bail!(ContextNotExists),
         }
     }
 }
+
+/// The error of creating span when the spans of the request exceed the limit,
+/// the hooks are skipped quietly by it.
+#[derive(Debug)]
+pub struct SpanLimitExceeded;
+
+impl Display for SpanLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("span limit per segment exceeded")
+    }
+}
+
+impl Error for SpanLimitExceeded {}
+
+/// The error when the global request context not exists, like the functions
+/// called in cli mode outside the consumers.
+#[derive(Debug)]
+pub struct ContextNotExists;
+
+impl Display for ContextNotExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("global request context not exists")
+    }
+}
+
+impl Error for ContextNotExists {}
@@ -8,21 +8,34 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use crate::{module::is_ready_for_request, plugin::select_plugin, util::catch_unwind_anyhow};
+use crate::{
+    cds::is_plugin_disabled,
+    context::{ContextNotExists, SpanLimitExceeded},
+    module::{is_ready_for_request, IS_CLI},
+    plugin::{select_hook_target, HookTarget},
+    request::is_request_skipped,
+    util::catch_unwind_anyhow,
+    SKYWALKING_AGENT_HOOK_USER_FUNCTIONS,
+};
 use anyhow::{bail, Context};
 use phper::{
+    eg,
+    ini::Ini,
     objects::ZObj,
-    strings::ZStr,
     sys,
     values::{ExecuteData, ZVal},
 };
 use std::{any::Any, panic::AssertUnwindSafe};
 use tracing::error;
 
-pub type BeforeExecuteHook = dyn FnOnce(&mut ExecuteData) -> anyhow::Result<Box<dyn Any>>;
+/// The hooks are created once for the function, and called by all the calls.
+pub type BeforeExecuteHook = dyn Fn(&mut ExecuteData) -> anyhow::Result<Box<dyn Any>>;
 
+/// Notice that for user functions, the arguments and `$this` have been released
+/// when the after hook is called, so the execute data is `None`, take what is
+/// needed in the before hook.
 pub type AfterExecuteHook =
-    dyn FnOnce(Box<dyn Any>, &mut ExecuteData, &mut ZVal) -> anyhow::Result<()>;
+    dyn Fn(Box<dyn Any>, Option<&mut ExecuteData>, &mut ZVal) -> anyhow::Result<()>;
 
 pub trait Noop {
     fn noop() -> Self;
@@ -41,7 +54,7 @@ impl Noop for Box<BeforeExecuteHook> {
 impl Noop for Box<AfterExecuteHook> {
     #[inline]
     fn noop() -> Self {
-        fn f(_: Box<dyn Any>, _: &mut ExecuteData, _: &mut ZVal) -> anyhow::Result<()> {
+        fn f(_: Box<dyn Any>, _: Option<&mut ExecuteData>, _: &mut ZVal) -> anyhow::Result<()> {
             Ok(())
         }
         Box::new(f)
@@ -52,11 +65,14 @@ static mut ORI_EXECUTE_INTERNAL: Option<
     unsafe extern "C" fn(execute_data: *mut sys::zend_execute_data, return_value: *mut sys::zval),
 > = None;
 
+static mut ORI_EXECUTE_EX: Option<unsafe extern "C" fn(execute_data: *mut sys::zend_execute_data)> =
+    None;
+
 #[tracing::instrument(skip_all)]
 unsafe extern "C" fn execute_internal(
     execute_data: *mut sys::zend_execute_data, return_value: *mut sys::zval,
 ) {
-    if !is_ready_for_request() {
+    if !is_ready_for_request() || is_request_skipped() {
         raw_ori_execute_internal(execute_data, return_value);
         return;
     }
@@ -64,63 +80,113 @@ unsafe extern "C" fn execute_internal(
     let execute_data = ExecuteData::from_mut_ptr(execute_data);
     let return_value = ZVal::from_mut_ptr(return_value);
 
-    let function = execute_data.func();
+    execute_with_hooks(execute_data, return_value, true, ori_execute_internal);
+}
 
-    let function_name = function.get_function_name();
-    let function_name = match function_name.to_str().ok() {
-        Some(s) => s,
-        None => {
-            ori_execute_internal(execute_data, return_value);
-            return;
-        }
-    };
-    let function_name = function_name.to_owned();
-
-    let is_class = !(*function.as_ptr()).common.scope.is_null()
-        && !((*(*function.as_ptr()).common.scope).name.is_null());
-    let class_name = if is_class {
-        ZStr::from_ptr((*(*function.as_ptr()).common.scope).name)
-            .to_str()
-            .ok()
+/// Hook the user functions (the functions written in php).
+///
+/// Notice that the arguments and `$this` of user functions have been released
+/// when the after hook is called, so only use them in the before hook.
+#[tracing::instrument(skip_all)]
+unsafe extern "C" fn execute_ex(execute_data: *mut sys::zend_execute_data) {
+    if !is_ready_for_request() || is_request_skipped() || is_generator(execute_data) {
+        raw_ori_execute_ex(execute_data);
+        return;
+    }
+
+    // The return value will be null if the caller doesn't use it.
+    let return_value = (*execute_data).return_value;
+    let mut null_return_value = ZVal::from(());
+    let return_value = if return_value.is_null() {
+        &mut null_return_value
     } else {
-        None
+        ZVal::from_mut_ptr(return_value)
     };
-    let class_name = class_name.map(ToOwned::to_owned);
 
-    let plugin = select_plugin(class_name.as_deref(), &function_name);
-    let plugin = match plugin {
-        Some(plugin) => plugin,
-        None => {
-            ori_execute_internal(execute_data, return_value);
-            return;
-        }
-    };
+    let execute_data = ExecuteData::from_mut_ptr(execute_data);
+
+    execute_with_hooks(execute_data, return_value, false, |execute_data, _| {
+        raw_ori_execute_ex(execute_data.as_mut_ptr())
+    });
+}
 
-    let (before, after) = match plugin.hook(class_name.as_deref(), &function_name) {
-        Some(hook) => hook,
-        None => {
-            ori_execute_internal(execute_data, return_value);
+unsafe fn execute_with_hooks(
+    execute_data: &mut ExecuteData, return_value: &mut ZVal, is_internal: bool,
+    ori_execute: impl Fn(&mut ExecuteData, &mut ZVal),
+) {
+    // The unhooked functions are returned here, by only one lookup of the index.
+    let target = match select_hook_target((*execute_data.as_mut_ptr()).func) {
+        Some(target) if !is_plugin_disabled(target.plugin.name()) => target,
+        _ => {
+            ori_execute(execute_data, return_value);
             return;
         }
     };
 
+    if !target.is_inherited && is_called_by_override(execute_data, &target) {
+        ori_execute(execute_data, return_value);
+        return;
+    }
+
+    let (before, after) = &*target.hooks;
+
     let result = catch_unwind_anyhow(AssertUnwindSafe(|| before(execute_data)));
     if let Err(e) = &result {
-        error!("before execute: {:?}", e);
+        if !is_quiet_error(e) {
+            error!("before execute: {:?}", e);
+        }
     }
 
-    ori_execute_internal(execute_data, return_value);
+    ori_execute(execute_data, return_value);
 
     // If before hook return error, don't execute the after hook.
     if let Ok(data) = result {
+        let execute_data = if is_internal { Some(execute_data) } else { None };
         if let Err(e) =
             catch_unwind_anyhow(AssertUnwindSafe(|| after(data, execute_data, return_value)))
         {
-            error!("after execute: {:?}", e);
+            if !is_quiet_error(&e) {
+                error!("after execute: {:?}", e);
+            }
         }
     }
 }
 
+/// The spans exceeding the limit are skipped quietly, not errors, and so are
+/// the calls without request context in cli mode, which are only traced in
+/// the consumers.
+fn is_quiet_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<SpanLimitExceeded>().is_some()
+        || (*IS_CLI && e.downcast_ref::<ContextNotExists>().is_some())
+}
+
+/// Whether the function is a generator, which is executed again on every
+/// resume, so it isn't hooked.
+unsafe fn is_generator(execute_data: *const sys::zend_execute_data) -> bool {
+    match (*execute_data).func.as_ref() {
+        Some(function) => function.common.fn_flags & sys::ZEND_ACC_GENERATOR != 0,
+        None => false,
+    }
+}
+
+/// Whether the method is called by the one overriding it, like
+/// `parent::dispatch()`, which is traced by the overriding one already.
+unsafe fn is_called_by_override(execute_data: &mut ExecuteData, target: &HookTarget) -> bool {
+    if target.class_name.is_none() {
+        return false;
+    }
+
+    let caller = match (*execute_data.as_mut_ptr()).prev_execute_data.as_ref() {
+        Some(caller) => caller.func,
+        None => return false,
+    };
+
+    matches!(
+        select_hook_target(caller),
+        Some(caller_target) if caller_target.is_inherited && caller_target.is_same_hook(target)
+    )
+}
+
 #[inline]
 fn ori_execute_internal(execute_data: &mut ExecuteData, return_value: &mut ZVal) {
     unsafe { raw_ori_execute_internal(execute_data.as_mut_ptr(), return_value.as_mut_ptr()) }
@@ -136,10 +202,26 @@ unsafe fn raw_ori_execute_internal(
     }
 }
 
+#[inline]
+unsafe fn raw_ori_execute_ex(execute_data: *mut sys::zend_execute_data) {
+    match ORI_EXECUTE_EX {
+        Some(f) => f(execute_data),
+        None => sys::execute_ex(execute_data),
+    }
+}
+
+/// Register the execute functions, the `zend_execute_ex` is only overridden
+/// when the user functions are hooked, because it disables the call frames
+/// without recursion of the vm, and makes every call of user function slower.
 pub fn register_execute_functions() {
     unsafe {
         ORI_EXECUTE_INTERNAL = sys::zend_execute_internal;
         sys::zend_execute_internal = Some(execute_internal);
+
+        if Ini::get::<bool>(SKYWALKING_AGENT_HOOK_USER_FUNCTIONS).unwrap_or_default() {
+            ORI_EXECUTE_EX = sys::zend_execute_ex;
+            sys::zend_execute_ex = Some(execute_ex);
+        }
     }
 }
 
@@ -153,3 +235,8 @@ pub fn validate_num_args(execute_data: &mut ExecuteData, num: usize) -> anyhow::
 pub fn get_this_mut(execute_data: &mut ExecuteData) -> anyhow::Result<&mut ZObj> {
     execute_data.get_this_mut().context("$this is empty")
 }
+
+/// Get the exception thrown by the executed function, used in after hooks.
+pub fn get_exception<'a>() -> Option<&'a ZObj> {
+    unsafe { eg!(exception).as_ref().map(|e| ZObj::from_ptr(e)) }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! The section of `phpinfo()`, shows the agent state, the plugins and the ini
+//! entries.
+
+use crate::{
+    cds::{applied_uuid, CDS_ENABLE},
+    module::{current_service_name, is_agent_enabled, is_ready_for_request, server_addr},
+    plugin::{is_plugin_enabled, plugins},
+};
+use phper::sys;
+use std::{ffi::CString, os::raw::c_char};
+use tracing::warn;
+
+/// Replace the info function of the module entry registered, which only
+/// prints the ini entries.
+pub fn register_module_info() {
+    let name = env!("CARGO_CRATE_NAME");
+    unsafe {
+        let entry = sys::zend_hash_str_find(
+            &sys::module_registry,
+            name.as_ptr().cast(),
+            name.len() as sys::size_t,
+        );
+        match entry.as_ref() {
+            Some(entry) => {
+                let module = entry.value.ptr as *mut sys::zend_module_entry;
+                (*module).info_func = Some(module_info);
+            }
+            None => warn!("module entry not found, phpinfo isn't registered"),
+        }
+    }
+}
+
+unsafe extern "C" fn module_info(zend_module: *mut sys::zend_module_entry) {
+    sys::php_info_print_table_start();
+    print_row(&["skywalking_agent support", "enabled"]);
+    print_row(&["Version", env!("CARGO_PKG_VERSION")]);
+    if is_agent_enabled() {
+        print_row(&["Agent", "enabled"]);
+        print_row(&["Service name", &current_service_name()]);
+        print_row(&["Server address", &server_addr()]);
+        print_row(&[
+            "Connection",
+            if is_ready_for_request() { "connected" } else { "connecting" },
+        ]);
+        let dynamic_configuration = if *CDS_ENABLE {
+            applied_uuid().unwrap_or_else(|| "not received".to_owned())
+        } else {
+            "disabled".to_owned()
+        };
+        print_row(&["Dynamic configuration", &dynamic_configuration]);
+    } else {
+        print_row(&["Agent", "disabled"]);
+    }
+    sys::php_info_print_table_end();
+
+    sys::php_info_print_table_start();
+    print_header(&["Plugin", "Component IDs", "Status"]);
+    for plugin in plugins() {
+        let component_ids = plugin
+            .component_ids()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let status = if is_plugin_enabled(plugin) { "active" } else { "disabled" };
+        print_row(&[plugin.name(), &component_ids, status]);
+    }
+    sys::php_info_print_table_end();
+
+    sys::display_ini_entries(zend_module);
+}
+
+/// The columns are passed as the variadic arguments, so only support to 3
+/// columns.
+unsafe fn print_row(columns: &[&str]) {
+    with_c_columns(columns, |num, c| match c {
+        [a] => sys::php_info_print_table_row(num, *a),
+        [a, b] => sys::php_info_print_table_row(num, *a, *b),
+        [a, b, c] => sys::php_info_print_table_row(num, *a, *b, *c),
+        _ => {}
+    });
+}
+
+unsafe fn print_header(columns: &[&str]) {
+    with_c_columns(columns, |num, c| match c {
+        [a] => sys::php_info_print_table_header(num, *a),
+        [a, b] => sys::php_info_print_table_header(num, *a, *b),
+        [a, b, c] => sys::php_info_print_table_header(num, *a, *b, *c),
+        _ => {}
+    });
+}
+
+fn with_c_columns(columns: &[&str], f: impl FnOnce(i32, &[*const c_char])) {
+    let columns = columns
+        .iter()
+        .map(|column| CString::new(*column).unwrap_or_default())
+        .collect::<Vec<_>>();
+    let ptrs = columns.iter().map(|column| column.as_ptr()).collect::<Vec<_>>();
+    f(ptrs.len() as i32, &ptrs);
+}
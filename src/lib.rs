@@ -12,12 +12,21 @@
 #![warn(clippy::dbg_macro, clippy::print_stdout)]
 #![doc = include_str!("../README.md")]
 
+mod api;
+mod capture;
+mod cds;
 mod channel;
+mod client_ip;
 mod component;
 mod context;
 mod execute;
+mod info;
+mod log_reporter;
+mod meter;
 mod module;
 mod plugin;
+mod propagation;
+mod redact;
 mod request;
 mod util;
 mod worker;
@@ -31,6 +40,10 @@ use phper::{
 /// Enable agent and report or not.
 const SKYWALKING_AGENT_ENABLE: &str = "skywalking_agent.enable";
 
+/// Enable agent in cli mode or not, for long running workers such as queue
+/// consumers.
+const SKYWALKING_AGENT_ENABLE_CLI: &str = "skywalking_agent.enable_cli";
+
 /// Version of skywalking server.
 const SKYWALKING_AGENT_VERSION: &str = "skywalking_agent.version";
 
@@ -52,6 +65,117 @@ const SKYWALKING_AGENT_LOG_FILE: &str = "skywalking_agent.log_file";
 /// Max message length to report to skywalking.
 const SKYWALKING_AGENT_MAX_MESSAGE_LENGTH: &str = "skywalking_agent.max_message_length";
 
+/// Report the request body (DSL) of elasticsearch or not.
+const SKYWALKING_AGENT_ELASTICSEARCH_TRACE_DSL: &str = "skywalking_agent.elasticsearch_trace_dsl";
+
+/// Methods and functions to trace in local span, see the plugin `custom`.
+const SKYWALKING_AGENT_CUSTOM_METHODS: &str = "skywalking_agent.custom_methods";
+
+/// Max number of the correlation elements, carried by `sw8-correlation`.
+const SKYWALKING_AGENT_CORRELATION_ELEMENT_MAX_NUMBER: &str =
+    "skywalking_agent.correlation_element_max_number";
+
+/// Max length of the correlation value.
+const SKYWALKING_AGENT_CORRELATION_VALUE_MAX_LENGTH: &str =
+    "skywalking_agent.correlation_value_max_length";
+
+/// Propagators to extract and inject, separated by comma, available values are
+/// `sw8`, `tracecontext` and `b3`.
+const SKYWALKING_AGENT_PROPAGATORS: &str = "skywalking_agent.propagators";
+
+/// Prefix the messages of `error_log()` and the php error log with
+/// `[sw:TRACE_ID]` or not.
+const SKYWALKING_AGENT_ERROR_LOG_TRACE_ID: &str = "skywalking_agent.error_log_trace_id";
+
+/// Report the application logs (by monolog and `error_log()`) or not.
+const SKYWALKING_AGENT_LOG_REPORTER_ENABLE: &str = "skywalking_agent.log_reporter_enable";
+
+/// Min level of the reported logs, like `WARNING`.
+const SKYWALKING_AGENT_LOG_REPORTER_LEVEL: &str = "skywalking_agent.log_reporter_level";
+
+/// Max count of the reported logs per request.
+const SKYWALKING_AGENT_LOG_REPORTER_MAX_PER_REQUEST: &str =
+    "skywalking_agent.log_reporter_max_per_request";
+
+/// Report the runtime meters of php (memory, opcache, fpm pool, gc and request
+/// durations) or not.
+const SKYWALKING_AGENT_METER_ENABLE: &str = "skywalking_agent.meter_enable";
+
+/// Interval in seconds to report the runtime meters.
+const SKYWALKING_AGENT_METER_REPORT_INTERVAL: &str = "skywalking_agent.meter_report_interval";
+
+/// Mode of the tag `db.statement`, available values are `off`, `raw` and
+/// `normalized` (literals replaced with `?`).
+const SKYWALKING_AGENT_SQL_STATEMENT_MODE: &str = "skywalking_agent.sql_statement_mode";
+
+/// Max length of the tags `db.statement` and `db.sql.parameters`, the longer
+/// ones are truncated.
+const SKYWALKING_AGENT_SQL_STATEMENT_MAX_LENGTH: &str = "skywalking_agent.sql_statement_max_length";
+
+/// Capture the bound parameters of PDO statements as the tag
+/// `db.sql.parameters` or not.
+const SKYWALKING_AGENT_SQL_PARAMETERS_ENABLE: &str = "skywalking_agent.sql_parameters_enable";
+
+/// Keywords of the parameter names whose values are masked, separated by comma.
+const SKYWALKING_AGENT_SQL_PARAMETERS_MASK: &str = "skywalking_agent.sql_parameters_mask";
+
+/// Query parameters whose values are masked in urls, separated by comma.
+const SKYWALKING_AGENT_REDACT_QUERY_PARAMS: &str = "skywalking_agent.redact_query_params";
+
+/// Headers whose values are masked, separated by comma.
+const SKYWALKING_AGENT_REDACT_HEADERS: &str = "skywalking_agent.redact_headers";
+
+/// Regexes to mask the matches in tag values, separated by `;` or newline.
+const SKYWALKING_AGENT_REDACT_PATTERNS: &str = "skywalking_agent.redact_patterns";
+
+/// Request headers captured as the tags of entry span, separated by comma.
+const SKYWALKING_AGENT_HTTP_CAPTURE_HEADERS: &str = "skywalking_agent.http_capture_headers";
+
+/// Parameters of `$_GET` and `$_POST` captured as the tag of entry span,
+/// separated by comma.
+const SKYWALKING_AGENT_HTTP_CAPTURE_PARAMS: &str = "skywalking_agent.http_capture_params";
+
+/// Max length of the captured header and parameter values.
+const SKYWALKING_AGENT_HTTP_CAPTURE_MAX_LENGTH: &str = "skywalking_agent.http_capture_max_length";
+
+/// Request headers of cURL captured as the tags of exit span, separated by
+/// comma.
+const SKYWALKING_AGENT_CURL_CAPTURE_REQUEST_HEADERS: &str =
+    "skywalking_agent.curl_capture_request_headers";
+
+/// Response headers of cURL captured as the tags of exit span, separated by
+/// comma, only available when `CURLOPT_HEADER` is enabled.
+const SKYWALKING_AGENT_CURL_CAPTURE_RESPONSE_HEADERS: &str =
+    "skywalking_agent.curl_capture_response_headers";
+
+/// Trusted proxies in CIDR, separated by comma, the forwarded headers are only
+/// honoured when the immediate peer is trusted.
+const SKYWALKING_AGENT_TRUSTED_PROXIES: &str = "skywalking_agent.trusted_proxies";
+
+/// Template of the service instance name, the placeholders are `{hostname}`,
+/// `{ip}`, `{pid}`, `{uuid}` and `{env:VAR}`.
+const SKYWALKING_AGENT_INSTANCE_NAME: &str = "skywalking_agent.instance_name";
+
+/// Namespace appended to the service name, like `service|namespace`.
+const SKYWALKING_AGENT_NAMESPACE: &str = "skywalking_agent.namespace";
+
+/// Cluster appended to the service name, like `service|namespace|cluster`.
+const SKYWALKING_AGENT_CLUSTER: &str = "skywalking_agent.cluster";
+
+/// Fetch the dynamic configuration by the configuration discovery service
+/// (CDS) of skywalking server or not.
+const SKYWALKING_AGENT_CDS_ENABLE: &str = "skywalking_agent.cds_enable";
+
+/// Interval in seconds to fetch the dynamic configuration.
+const SKYWALKING_AGENT_CDS_FETCH_INTERVAL: &str = "skywalking_agent.cds_fetch_interval";
+
+/// Plugins not hooked, separated by comma, like `curl,pdo`.
+const SKYWALKING_AGENT_PLUGINS_DISABLED: &str = "skywalking_agent.plugins.disabled";
+
+/// Hook the user functions (written in php) or not, required by the plugins of
+/// the frameworks and libraries written in php, such as `laravel`.
+const SKYWALKING_AGENT_HOOK_USER_FUNCTIONS: &str = "skywalking_agent.hook_user_functions";
+
 #[php_get_module]
 pub fn get_module() -> Module {
     let mut module = Module::new(
@@ -62,6 +186,7 @@ pub fn get_module() -> Module {
 
     // Register skywalking_agent ini.
     Ini::add(SKYWALKING_AGENT_ENABLE, false, Policy::System);
+    Ini::add(SKYWALKING_AGENT_ENABLE_CLI, false, Policy::System);
     Ini::add(SKYWALKING_AGENT_VERSION, 9i64, Policy::System);
     Ini::add(
         SKYWALKING_AGENT_SERVER_ADDR,
@@ -89,6 +214,128 @@ pub fn get_module() -> Module {
         81920i64,
         Policy::System,
     );
+    Ini::add(SKYWALKING_AGENT_ELASTICSEARCH_TRACE_DSL, false, Policy::System);
+    Ini::add(
+        SKYWALKING_AGENT_CUSTOM_METHODS,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_CORRELATION_ELEMENT_MAX_NUMBER,
+        3i64,
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_CORRELATION_VALUE_MAX_LENGTH,
+        128i64,
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_PROPAGATORS,
+        "sw8".to_string(),
+        Policy::System,
+    );
+    Ini::add(SKYWALKING_AGENT_ERROR_LOG_TRACE_ID, false, Policy::System);
+    Ini::add(SKYWALKING_AGENT_LOG_REPORTER_ENABLE, false, Policy::System);
+    Ini::add(
+        SKYWALKING_AGENT_LOG_REPORTER_LEVEL,
+        "WARNING".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_LOG_REPORTER_MAX_PER_REQUEST,
+        100i64,
+        Policy::System,
+    );
+    Ini::add(SKYWALKING_AGENT_METER_ENABLE, false, Policy::System);
+    Ini::add(SKYWALKING_AGENT_METER_REPORT_INTERVAL, 20i64, Policy::System);
+    Ini::add(
+        SKYWALKING_AGENT_SQL_STATEMENT_MODE,
+        "raw".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_SQL_STATEMENT_MAX_LENGTH,
+        2048i64,
+        Policy::System,
+    );
+    Ini::add(SKYWALKING_AGENT_SQL_PARAMETERS_ENABLE, false, Policy::System);
+    Ini::add(
+        SKYWALKING_AGENT_SQL_PARAMETERS_MASK,
+        "password,passwd,pwd,secret,token".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_REDACT_QUERY_PARAMS,
+        "access_token,api_key,apikey,password,secret,sign,signature,token".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_REDACT_HEADERS,
+        "authorization,cookie,proxy-authorization,set-cookie,x-api-key".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_REDACT_PATTERNS,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_HTTP_CAPTURE_HEADERS,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_HTTP_CAPTURE_PARAMS,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_HTTP_CAPTURE_MAX_LENGTH,
+        256i64,
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_CURL_CAPTURE_REQUEST_HEADERS,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_CURL_CAPTURE_RESPONSE_HEADERS,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_TRUSTED_PROXIES,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_INSTANCE_NAME,
+        "{uuid}@{ip}".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_NAMESPACE,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(
+        SKYWALKING_AGENT_CLUSTER,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(SKYWALKING_AGENT_CDS_ENABLE, false, Policy::System);
+    Ini::add(SKYWALKING_AGENT_CDS_FETCH_INTERVAL, 20i64, Policy::System);
+    Ini::add(
+        SKYWALKING_AGENT_PLUGINS_DISABLED,
+        "".to_string(),
+        Policy::System,
+    );
+    Ini::add(SKYWALKING_AGENT_HOOK_USER_FUNCTIONS, false, Policy::System);
+
+    // Manual instrumentation api.
+    api::register_api(&mut module);
 
     // Hooks.
     module.on_module_init(module::init);
@@ -0,0 +1,134 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Report the application logs (by monolog and `error_log()`) to skywalking,
+//! with the trace context attached.
+//!
+//! Only the logs in the traced request are reported, the logs are sent to the
+//! worker by the channel, and reported by `LogReportService/collect`.
+
+use crate::{
+    channel::send_log,
+    context::RequestContext,
+    module::{current_service_name, SERVICE_INSTANCE},
+    redact::redact_log,
+    SKYWALKING_AGENT_LOG_REPORTER_ENABLE, SKYWALKING_AGENT_LOG_REPORTER_LEVEL,
+    SKYWALKING_AGENT_LOG_REPORTER_MAX_PER_REQUEST,
+};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use phper::ini::Ini;
+use skywalking::skywalking_proto::v3::{
+    log_data_body::Content, KeyStringValuePair, LogData, LogDataBody, LogTags, TextLog,
+    TraceContext,
+};
+use tracing::{debug, warn};
+
+/// The log levels of RFC 5424, same as monolog.
+const LEVELS: &[(&str, i64)] = &[
+    ("DEBUG", 100),
+    ("INFO", 200),
+    ("NOTICE", 250),
+    ("WARNING", 300),
+    ("ERROR", 400),
+    ("CRITICAL", 500),
+    ("ALERT", 550),
+    ("EMERGENCY", 600),
+];
+
+/// The level of the messages of `error_log()`.
+pub const ERROR_LOG_LEVEL: i64 = 400;
+
+pub static LOG_REPORTER_ENABLE: Lazy<bool> =
+    Lazy::new(|| Ini::get::<bool>(SKYWALKING_AGENT_LOG_REPORTER_ENABLE).unwrap_or_default());
+
+static MIN_LEVEL: Lazy<i64> = Lazy::new(|| {
+    let level = Ini::get::<String>(SKYWALKING_AGENT_LOG_REPORTER_LEVEL).unwrap_or_default();
+    LEVELS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(level.trim()))
+        .map(|(_, level)| *level)
+        .unwrap_or_else(|| {
+            warn!(level, "unknown log reporter level, use WARNING");
+            300
+        })
+});
+
+static MAX_PER_REQUEST: Lazy<usize> = Lazy::new(|| {
+    Ini::get::<i64>(SKYWALKING_AGENT_LOG_REPORTER_MAX_PER_REQUEST).unwrap_or(100) as usize
+});
+
+/// Get the level name, the unknown level is rounded down to the known one.
+fn level_name(level: i64) -> &'static str {
+    LEVELS
+        .iter()
+        .rev()
+        .find(|(_, l)| *l <= level)
+        .map(|(name, _)| *name)
+        .unwrap_or("DEBUG")
+}
+
+/// Report the log after redacted, if the level reaches the threshold, and the
+/// count of the request doesn't exceed the cap.
+pub fn report_log(
+    level: i64, message: String, tags: Vec<(&'static str, String)>,
+) -> anyhow::Result<()> {
+    if !*LOG_REPORTER_ENABLE || level < *MIN_LEVEL {
+        return Ok(());
+    }
+
+    let log = RequestContext::with_global(None, |ctx| {
+        if ctx.log_count >= *MAX_PER_REQUEST {
+            return None;
+        }
+        ctx.log_count += 1;
+
+        let mut endpoint = String::new();
+        ctx.entry_span
+            .with_span_object_mut(|span| endpoint = span.operation_name.clone());
+
+        let mut data = vec![KeyStringValuePair {
+            key: "level".to_owned(),
+            value: level_name(level).to_owned(),
+        }];
+        data.extend(tags.into_iter().map(|(key, value)| KeyStringValuePair {
+            key: key.to_owned(),
+            value: redact_log(&value).into_owned(),
+        }));
+
+        Some(LogData {
+            timestamp: Utc::now().timestamp_millis(),
+            service: current_service_name(),
+            service_instance: SERVICE_INSTANCE.clone(),
+            endpoint,
+            body: Some(LogDataBody {
+                r#type: "text".to_owned(),
+                content: Some(Content::Text(TextLog {
+                    text: redact_log(&message).into_owned(),
+                })),
+            }),
+            trace_context: Some(TraceContext {
+                trace_id: ctx.tracing_context.trace_id().to_owned(),
+                trace_segment_id: ctx.tracing_context.trace_segment_id().to_owned(),
+                span_id: ctx.tracing_context.peek_active_span_id().unwrap_or(0),
+            }),
+            tags: Some(LogTags { data }),
+            ..Default::default()
+        })
+    });
+
+    match log.flatten() {
+        Some(log) => send_log(log),
+        None => {
+            debug!("log isn't reported, out of request or exceeds the cap");
+            Ok(())
+        }
+    }
+}
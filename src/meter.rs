@@ -0,0 +1,306 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Runtime meters of php, reported by `MeterReportService/collect`.
+//!
+//! Each FPM child records the request durations, and collects the runtime
+//! status (memory, opcache, fpm pool and gc) at request shutdown, at most once
+//! per report interval, then sends them to the worker by the channel. The
+//! worker aggregates the metrics of children and reports them periodically.
+
+use crate::{
+    channel::send_meter,
+    module::{current_service_name, SERVICE_INSTANCE},
+    SKYWALKING_AGENT_METER_ENABLE, SKYWALKING_AGENT_METER_REPORT_INTERVAL,
+};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use phper::{arrays::ZArr, functions::call, ini::Ini, sys, values::ZVal};
+use serde::{Deserialize, Serialize};
+use skywalking::skywalking_proto::v3::{
+    meter_data::Metric, Label, MeterBucketValue, MeterData, MeterHistogram, MeterSingleValue,
+};
+use std::{
+    cell::RefCell,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// The lower bounds (in milliseconds) of the request duration buckets.
+const DURATION_BUCKETS: &[u64] = &[0, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+pub static METER_ENABLE: Lazy<bool> =
+    Lazy::new(|| Ini::get::<bool>(SKYWALKING_AGENT_METER_ENABLE).unwrap_or_default());
+
+pub static REPORT_INTERVAL: Lazy<Duration> = Lazy::new(|| {
+    let interval = Ini::get::<i64>(SKYWALKING_AGENT_METER_REPORT_INTERVAL).unwrap_or(0);
+    Duration::from_secs(if interval <= 0 { 20 } else { interval as u64 })
+});
+
+/// The metrics sent from the php processes to the worker.
+#[derive(Serialize, Deserialize, Default)]
+pub struct RuntimeMetrics {
+    /// The service name of the process, the FPM pools may be different.
+    service: String,
+    memory_usage: u64,
+    memory_peak_usage: u64,
+    opcache: Option<OpcacheMetrics>,
+    fpm: Option<FpmMetrics>,
+    /// The gc runs since the last sending.
+    gc_runs: u64,
+    /// The request counts of [DURATION_BUCKETS] since the last sending.
+    durations: Vec<u64>,
+}
+
+impl RuntimeMetrics {
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct OpcacheMetrics {
+    hit_rate: f64,
+    used_memory: u64,
+    free_memory: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct FpmMetrics {
+    pool: String,
+    active_processes: u64,
+    idle_processes: u64,
+    listen_queue: u64,
+}
+
+/// The metrics recorded by the php process, between the sendings.
+struct LocalMeter {
+    last_sent: Instant,
+    last_gc_runs: Option<u64>,
+    durations: Vec<u64>,
+}
+
+thread_local! {
+    static LOCAL_METER: RefCell<LocalMeter> = RefCell::new(LocalMeter {
+        last_sent: Instant::now(),
+        last_gc_runs: None,
+        durations: vec![0; DURATION_BUCKETS.len()],
+    });
+}
+
+/// Record the request duration, and send the runtime metrics to the worker if
+/// the report interval is elapsed, should be called in request shutdown.
+pub fn record_request(duration_ms: u64) -> anyhow::Result<()> {
+    if !*METER_ENABLE {
+        return Ok(());
+    }
+
+    LOCAL_METER.with(|meter| {
+        let mut meter = meter.borrow_mut();
+        meter.durations[bucket_index(duration_ms)] += 1;
+
+        if meter.last_sent.elapsed() < *REPORT_INTERVAL {
+            return Ok(());
+        }
+        meter.last_sent = Instant::now();
+
+        let gc_runs = get_gc_runs();
+        let gc_runs_delta = match (gc_runs, meter.last_gc_runs) {
+            (Some(runs), Some(last_runs)) => runs.saturating_sub(last_runs),
+            _ => 0,
+        };
+        if gc_runs.is_some() {
+            meter.last_gc_runs = gc_runs;
+        }
+
+        let metrics = RuntimeMetrics {
+            service: current_service_name(),
+            memory_usage: unsafe { sys::zend_memory_usage(false) } as u64,
+            memory_peak_usage: unsafe { sys::zend_memory_peak_usage(false) } as u64,
+            opcache: get_opcache_metrics(),
+            fpm: get_fpm_metrics(),
+            gc_runs: gc_runs_delta,
+            durations: meter.durations.clone(),
+        };
+        meter.durations.iter_mut().for_each(|count| *count = 0);
+
+        send_meter(metrics)
+    })
+}
+
+fn bucket_index(duration_ms: u64) -> usize {
+    DURATION_BUCKETS
+        .iter()
+        .rposition(|bucket| *bucket <= duration_ms)
+        .unwrap_or(0)
+}
+
+/// Call the status function (like `opcache_get_status`) if exists, because
+/// the extensions may not be loaded.
+fn call_status_function(name: &str, arguments: &mut [ZVal]) -> Option<ZVal> {
+    let exists = call("function_exists", &mut [ZVal::from(name)]).ok()?;
+    if exists.as_bool() != Some(true) {
+        return None;
+    }
+    match call(name, arguments) {
+        Ok(status) => Some(status),
+        Err(err) => {
+            warn!(?err, name, "call status function failed");
+            None
+        }
+    }
+}
+
+fn get_long(arr: &ZArr, key: &str) -> Option<u64> {
+    arr.get(key).and_then(ZVal::as_long).map(|n| n.max(0) as u64)
+}
+
+fn get_opcache_metrics() -> Option<OpcacheMetrics> {
+    let status = call_status_function("opcache_get_status", &mut [ZVal::from(false)])?;
+    let status = status.as_z_arr()?;
+    let statistics = status.get("opcache_statistics")?.as_z_arr()?;
+    let memory = status.get("memory_usage")?.as_z_arr()?;
+    Some(OpcacheMetrics {
+        hit_rate: statistics
+            .get("opcache_hit_rate")
+            .and_then(ZVal::as_double)
+            .unwrap_or_default(),
+        used_memory: get_long(memory, "used_memory").unwrap_or_default(),
+        free_memory: get_long(memory, "free_memory").unwrap_or_default(),
+    })
+}
+
+/// The `fpm_get_status` is available since php 7.3.
+fn get_fpm_metrics() -> Option<FpmMetrics> {
+    let status = call_status_function("fpm_get_status", &mut [])?;
+    let status = status.as_z_arr()?;
+    Some(FpmMetrics {
+        pool: status
+            .get("pool")
+            .and_then(|pool| pool.as_z_str())
+            .and_then(|pool| pool.to_str().ok())
+            .unwrap_or_default()
+            .to_owned(),
+        active_processes: get_long(status, "active-processes").unwrap_or_default(),
+        idle_processes: get_long(status, "idle-processes").unwrap_or_default(),
+        listen_queue: get_long(status, "listen-queue").unwrap_or_default(),
+    })
+}
+
+/// The `gc_status` is available since php 7.3.
+fn get_gc_runs() -> Option<u64> {
+    let status = call_status_function("gc_status", &mut [])?;
+    get_long(status.as_z_arr()?, "runs")
+}
+
+/// Aggregate the metrics of php processes of the same service in worker.
+///
+/// The gauges of memory are the max of the interval, the gauges of opcache and
+/// fpm pool are the latest, and the counters of gc runs and request durations
+/// are accumulated since the worker started.
+#[derive(Default)]
+pub struct MeterAggregator {
+    memory_usage: Option<u64>,
+    memory_peak_usage: Option<u64>,
+    opcache: Option<OpcacheMetrics>,
+    fpm: Option<FpmMetrics>,
+    gc_runs: u64,
+    durations: Vec<u64>,
+}
+
+impl MeterAggregator {
+    pub fn merge(&mut self, metrics: RuntimeMetrics) {
+        self.memory_usage = self.memory_usage.max(Some(metrics.memory_usage));
+        self.memory_peak_usage = self.memory_peak_usage.max(Some(metrics.memory_peak_usage));
+        if metrics.opcache.is_some() {
+            self.opcache = metrics.opcache;
+        }
+        if metrics.fpm.is_some() {
+            self.fpm = metrics.fpm;
+        }
+        self.gc_runs += metrics.gc_runs;
+
+        if self.durations.len() < metrics.durations.len() {
+            self.durations.resize(metrics.durations.len(), 0);
+        }
+        for (total, count) in self.durations.iter_mut().zip(metrics.durations) {
+            *total += count;
+        }
+    }
+
+    /// Take the meter data to report, the gauges of memory are reset.
+    pub fn take_meter_data(&mut self, service: &str) -> Vec<MeterData> {
+        let mut values = Vec::new();
+
+        if let Some(memory_usage) = self.memory_usage.take() {
+            values.push(("php_memory_usage_bytes", vec![], memory_usage as f64));
+        }
+        if let Some(memory_peak_usage) = self.memory_peak_usage.take() {
+            values.push(("php_memory_peak_usage_bytes", vec![], memory_peak_usage as f64));
+        }
+        if let Some(opcache) = &self.opcache {
+            values.push(("php_opcache_hit_rate", vec![], opcache.hit_rate));
+            values.push(("php_opcache_used_memory_bytes", vec![], opcache.used_memory as f64));
+            values.push(("php_opcache_free_memory_bytes", vec![], opcache.free_memory as f64));
+        }
+        if let Some(fpm) = &self.fpm {
+            let labels = || {
+                vec![Label {
+                    name: "pool".to_owned(),
+                    value: fpm.pool.clone(),
+                }]
+            };
+            values.push(("php_fpm_active_processes", labels(), fpm.active_processes as f64));
+            values.push(("php_fpm_idle_processes", labels(), fpm.idle_processes as f64));
+            values.push(("php_fpm_listen_queue", labels(), fpm.listen_queue as f64));
+        }
+        values.push(("php_gc_runs_total", vec![], self.gc_runs as f64));
+
+        let mut metrics = values
+            .into_iter()
+            .map(|(name, labels, value)| {
+                Metric::SingleValue(MeterSingleValue {
+                    name: name.to_owned(),
+                    labels,
+                    value,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if !self.durations.is_empty() {
+            metrics.push(Metric::Histogram(MeterHistogram {
+                name: "php_request_duration_ms".to_owned(),
+                labels: vec![],
+                values: DURATION_BUCKETS
+                    .iter()
+                    .zip(&self.durations)
+                    .map(|(bucket, count)| MeterBucketValue {
+                        bucket: *bucket as f64,
+                        count: *count as i64,
+                        is_negative_infinity: false,
+                    })
+                    .collect(),
+            }));
+        }
+
+        let timestamp = Utc::now().timestamp_millis();
+        debug!(count = metrics.len(), "Take meter data");
+
+        metrics
+            .into_iter()
+            .map(|metric| MeterData {
+                metric: Some(metric),
+                service: service.to_owned(),
+                service_instance: SERVICE_INSTANCE.clone(),
+                timestamp,
+            })
+            .collect()
+    }
+}
@@ -9,50 +9,181 @@
 // See the Mulan PSL v2 for more details.
 
 use crate::{
+    cds::init_shared_config,
     channel::{self, init_channel},
     execute::register_execute_functions,
-    util::IPS,
+    info::register_module_info,
+    plugin::{register_log_message, warn_shadowed_custom_methods},
+    util::{HOST_NAME, IPS},
     worker::init_worker,
-    SKYWALKING_AGENT_ENABLE, SKYWALKING_AGENT_LOG_FILE, SKYWALKING_AGENT_LOG_LEVEL,
-    SKYWALKING_AGENT_SERVICE_NAME,
+    SKYWALKING_AGENT_CLUSTER, SKYWALKING_AGENT_ENABLE, SKYWALKING_AGENT_ENABLE_CLI,
+    SKYWALKING_AGENT_INSTANCE_NAME, SKYWALKING_AGENT_LOG_FILE, SKYWALKING_AGENT_LOG_LEVEL,
+    SKYWALKING_AGENT_NAMESPACE, SKYWALKING_AGENT_SERVER_ADDR, SKYWALKING_AGENT_SERVICE_NAME,
 };
 use ipc_channel::ipc::IpcSharedMemory;
 use once_cell::sync::Lazy;
 use phper::{ini::Ini, modules::ModuleContext, sys};
-use skywalking::{
-    common::random_generator::RandomGenerator,
-    context::tracer::{self, Tracer},
-};
+use skywalking::{common::random_generator::RandomGenerator, context::tracer::Tracer};
 use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
     ffi::CStr,
     intrinsics::transmute,
     mem::size_of,
     ops::Deref,
     path::Path,
+    process,
     str::FromStr,
     sync::atomic::{AtomicBool, Ordering},
 };
 use tracing::{error, info, metadata::LevelFilter};
 use tracing_subscriber::FmtSubscriber;
 
-pub static SERVICE_NAME: Lazy<String> =
-    Lazy::new(|| Ini::get::<String>(SKYWALKING_AGENT_SERVICE_NAME).unwrap_or_default());
+/// The service name of the process which loads the module, used by worker,
+/// the requests may have their own service names, see [current_service_name].
+pub static SERVICE_NAME: Lazy<String> = Lazy::new(current_service_name);
+
+/// The service instance name rendered by the template, should be evaluated in
+/// module init, so the FPM children share the same instance.
+pub static SERVICE_INSTANCE: Lazy<String> = Lazy::new(|| {
+    let template = get_str_config(SKYWALKING_AGENT_INSTANCE_NAME, "SW_AGENT_INSTANCE_NAME");
+    let template = template.trim();
+    render_instance_name(if template.is_empty() { "{uuid}@{ip}" } else { template })
+});
+
+pub static IS_CLI: Lazy<bool> = Lazy::new(|| get_sapi_module_name().to_bytes() == b"cli");
+
+/// The agent is enabled in module init or not.
+static IS_AGENT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static TRACERS: RefCell<HashMap<String, Tracer>> = Default::default();
+}
+
+/// Get the ini string, overridden by the environment variable like the other
+/// skywalking agents, such as `SW_AGENT_NAME`.
+pub fn get_str_config(ini: &str, env_name: &str) -> String {
+    match env::var(env_name) {
+        Ok(value) if !value.trim().is_empty() => value,
+        _ => Ini::get::<String>(ini).unwrap_or_default(),
+    }
+}
+
+/// Get the ini bool, overridden by the environment variable.
+pub fn get_bool_config(ini: &str, env_name: &str) -> bool {
+    match env::var(env_name).map(|value| value.trim().to_ascii_lowercase()) {
+        Ok(value) if !value.is_empty() => matches!(&*value, "1" | "true" | "on" | "yes"),
+        _ => Ini::get::<bool>(ini).unwrap_or_default(),
+    }
+}
+
+/// Get the service name of current process, so the FPM pools can have their
+/// own service names by `php_admin_value` or `env`, which are applied after
+/// the module init.
+///
+/// The namespace and cluster are appended like the java agent, such as
+/// `service|namespace|cluster`.
+pub fn current_service_name() -> String {
+    let mut service_name = get_str_config(SKYWALKING_AGENT_SERVICE_NAME, "SW_AGENT_NAME");
+    for (ini, env_name) in [
+        (SKYWALKING_AGENT_NAMESPACE, "SW_AGENT_NAMESPACE"),
+        (SKYWALKING_AGENT_CLUSTER, "SW_AGENT_CLUSTER"),
+    ] {
+        let value = get_str_config(ini, env_name);
+        let value = value.trim();
+        if !value.is_empty() {
+            service_name.push('|');
+            service_name.push_str(value);
+        }
+    }
+    service_name
+}
+
+/// Render the instance name template, the placeholders are `{hostname}`,
+/// `{ip}`, `{pid}`, `{uuid}` and `{env:VAR}`, the unknown ones are kept.
+fn render_instance_name(template: &str) -> String {
+    let mut instance_name = String::with_capacity(template.len());
+    let mut rest = template;
 
-pub static SERVICE_INSTANCE: Lazy<String> =
-    Lazy::new(|| RandomGenerator::generate() + "@" + &IPS[0]);
+    while let Some(start) = rest.find('{') {
+        instance_name.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let placeholder = &rest[1..end];
+        match placeholder {
+            "hostname" => instance_name.push_str(&HOST_NAME),
+            "ip" => instance_name.push_str(&IPS[0]),
+            "pid" => instance_name.push_str(&process::id().to_string()),
+            "uuid" => instance_name.push_str(&RandomGenerator::generate()),
+            _ => match placeholder.strip_prefix("env:") {
+                Some(name) => instance_name.push_str(&env::var(name).unwrap_or_default()),
+                None => instance_name.push_str(&rest[..=end]),
+            },
+        }
+        rest = &rest[end + 1..];
+    }
+
+    instance_name.push_str(rest);
+    instance_name
+}
+
+/// Get the skywalking server address, the address like `127.0.0.1:11800` of
+/// `SW_AGENT_COLLECTOR_BACKEND_SERVICES` is prefixed with `http://`.
+pub fn server_addr() -> String {
+    let server_addr =
+        get_str_config(SKYWALKING_AGENT_SERVER_ADDR, "SW_AGENT_COLLECTOR_BACKEND_SERVICES");
+    let server_addr = server_addr.trim();
+    if server_addr.contains("://") {
+        server_addr.to_owned()
+    } else {
+        format!("http://{server_addr}")
+    }
+}
+
+/// Get the tracer of current service name, the segments created by it are
+/// sent to the worker.
+pub fn current_tracer() -> Tracer {
+    let service_name = current_service_name();
+    TRACERS.with(|tracers| {
+        tracers
+            .borrow_mut()
+            .entry(service_name)
+            .or_insert_with_key(|service_name| {
+                Tracer::new_with_channel(
+                    service_name.as_str(),
+                    SERVICE_INSTANCE.as_str(),
+                    (),
+                    (channel::Sender, ()),
+                )
+            })
+            .clone()
+    })
+}
 
 pub fn init(_module: ModuleContext) -> bool {
-    // Now only support in FPM mode.
+    register_module_info();
+
+    // Now only support in FPM mode, and cli mode for long running workers.
     // TODO Support swoole, etc.
-    if get_sapi_module_name().to_bytes() != b"fpm-fcgi" {
+    let is_fpm = get_sapi_module_name().to_bytes() == b"fpm-fcgi";
+    let is_cli = *IS_CLI && Ini::get::<bool>(SKYWALKING_AGENT_ENABLE_CLI).unwrap_or_default();
+    if !is_fpm && !is_cli {
         return true;
     }
 
-    let enable = Ini::get::<bool>(SKYWALKING_AGENT_ENABLE).unwrap_or_default();
+    let enable = get_bool_config(SKYWALKING_AGENT_ENABLE, "SW_AGENT_ENABLE");
     if enable {
+        IS_AGENT_ENABLED.store(true, Ordering::SeqCst);
+
         init_logger();
 
         get_ready_for_request();
+        init_shared_config();
 
         if let Err(e) = init_channel() {
             error!("Init channel failed: {}", e);
@@ -65,14 +196,11 @@ pub fn init(_module: ModuleContext) -> bool {
 
         init_worker();
 
-        tracer::set_global_tracer(Tracer::new_with_channel(
-            service_name,
-            service_instance,
-            (),
-            (channel::Sender, ()),
-        ));
-
         register_execute_functions();
+
+        register_log_message();
+
+        warn_shadowed_custom_methods();
     }
 
     true
@@ -82,6 +210,10 @@ pub fn shutdown(_module: ModuleContext) -> bool {
     true
 }
 
+pub fn is_agent_enabled() -> bool {
+    IS_AGENT_ENABLED.load(Ordering::SeqCst)
+}
+
 pub fn is_ready_for_request() -> bool {
     get_ready_for_request().load(Ordering::SeqCst)
 }
@@ -0,0 +1,81 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [CodeIgniter 4](https://codeigniter.com/).
+
+use super::{after_hook_with_exception, create_local_span, Plugin};
+use crate::{
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook},
+    util::z_val_to_string,
+};
+use anyhow::Context;
+use tracing::debug;
+
+#[derive(Default, Clone)]
+pub struct CodeIgniterPlugin;
+
+impl Plugin for CodeIgniterPlugin {
+    fn name(&self) -> &'static str {
+        "codeigniter"
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["CodeIgniter\\CodeIgniter"];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("CodeIgniter\\CodeIgniter"), "runController") => Some(self.hook_run_controller()),
+            _ => None,
+        }
+    }
+}
+
+impl CodeIgniterPlugin {
+    /// Hook `CodeIgniter\CodeIgniter::runController($class)`, which is called
+    /// after the route resolved into `controller` and `method`, rename the
+    /// entry span with them.
+    fn hook_run_controller(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let controller = execute_data
+                    .get_parameter(0)
+                    .as_z_obj()
+                    .context("controller isn't object")?;
+                let controller = controller.get_class().get_name().to_str()?.to_owned();
+
+                let this = get_this_mut(execute_data)?;
+                let method = z_val_to_string(this.get_property("method"))
+                    .context("method isn't string")?;
+
+                let route = format!("{}::{}", controller, method);
+
+                debug!(route, "codeigniter route resolved");
+
+                RequestContext::rename_entry_span_with_route(None, &route)?;
+
+                let span = create_local_span(&format!("{}->{}", controller, method))?;
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+}
@@ -10,9 +10,13 @@
 
 use super::Plugin;
 use crate::{
+    capture::{add_header_line_tags, CURL_REQUEST_HEADERS, CURL_RESPONSE_HEADERS},
     component::COMPONENT_PHP_CURL_ID,
     context::RequestContext,
     execute::{validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    propagation::inject_propagation,
+    redact::{RedactedLog, RedactedTag},
+    util::z_val_to_string,
 };
 use anyhow::Context;
 use phper::{
@@ -20,21 +24,39 @@ use phper::{
     functions::call,
     values::{ExecuteData, ZVal},
 };
-use skywalking::context::{propagation::encoder::encode_propagation, span::Span};
-use std::{cell::RefCell, collections::HashMap, os::raw::c_long};
+use skywalking::context::span::Span;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    os::raw::c_long,
+};
 use tracing::debug;
 use url::Url;
 
 static CURLOPT_HTTPHEADER: c_long = 10023;
 
+static CURLOPT_HEADER: c_long = 42;
+
 thread_local! {
     static CURL_HEADERS: RefCell<HashMap<i64, ZVal>> = Default::default();
+    /// The handles with `CURLOPT_HEADER` enabled, whose response headers are in
+    /// the result of `curl_exec`.
+    static CURL_HEADER_OUTPUTS: RefCell<HashSet<i64>> = Default::default();
 }
 
 #[derive(Default, Clone)]
 pub struct CurlPlugin;
 
 impl Plugin for CurlPlugin {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "curl"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_PHP_CURL_ID]
+    }
+
     #[inline]
     fn class_names(&self) -> Option<&'static [&'static str]> {
         None
@@ -56,6 +78,13 @@ impl Plugin for CurlPlugin {
             _ => None,
         }
     }
+
+    /// The resource ids are reused by the next request, and the handles not
+    /// closed by `curl_close` are freed at the end of request.
+    fn clear(&self) {
+        CURL_HEADERS.with(|headers| headers.borrow_mut().clear());
+        CURL_HEADER_OUTPUTS.with(|outputs| outputs.borrow_mut().clear());
+    }
 }
 
 impl CurlPlugin {
@@ -73,6 +102,8 @@ impl CurlPlugin {
                         CURL_HEADERS
                             .with(|headers| headers.borrow_mut().insert(cid, value.clone()));
                     }
+                } else if execute_data.get_parameter(1).as_long() == Some(CURLOPT_HEADER) {
+                    Self::set_header_output(cid, execute_data.get_parameter(2));
                 }
 
                 Ok(Box::new(()))
@@ -93,6 +124,9 @@ impl CurlPlugin {
                         CURL_HEADERS
                             .with(|headers| headers.borrow_mut().insert(cid, value.clone()));
                     }
+                    if let Some(value) = opts.get(CURLOPT_HEADER as u64) {
+                        Self::set_header_output(cid, value);
+                    }
                 }
 
                 Ok(Box::new(()))
@@ -150,20 +184,32 @@ impl CurlPlugin {
 
                 span.with_span_object_mut(|span| {
                     span.component_id = COMPONENT_PHP_CURL_ID;
-                    span.add_tag("url", raw_url);
+                    span.add_redacted_tag("url", raw_url);
                 });
 
-                let sw_header = RequestContext::try_with_global_ctx(None, |ctx| {
-                    Ok(encode_propagation(ctx, url.path(), peer))
-                })?;
+                let headers = inject_propagation(None, url.path(), peer)?;
                 let mut val = CURL_HEADERS
                     .with(|headers| headers.borrow_mut().remove(&cid))
                     .unwrap_or_else(|| ZVal::from(ZArray::new()));
                 if let Some(arr) = val.as_mut_z_arr() {
-                    arr.insert(
-                        InsertKey::NextIndex,
-                        ZVal::from(format!("sw8: {}", sw_header)),
-                    );
+                    if !CURL_REQUEST_HEADERS.is_empty() {
+                        let lines = arr
+                            .iter()
+                            .filter_map(|(_, line)| z_val_to_string(line))
+                            .collect::<Vec<_>>();
+                        add_header_line_tags(
+                            &mut span,
+                            "http.headers.",
+                            &CURL_REQUEST_HEADERS,
+                            lines.iter().map(String::as_str),
+                        );
+                    }
+                    for (name, value) in headers {
+                        arr.insert(
+                            InsertKey::NextIndex,
+                            ZVal::from(format!("{}: {}", name, value)),
+                        );
+                    }
                     let ch = execute_data.get_parameter(0);
                     call(
                         "curl_setopt",
@@ -174,8 +220,9 @@ impl CurlPlugin {
 
                 Ok(Box::new(span))
             }),
-            Box::new(move |span, execute_data, _| {
+            Box::new(move |span, execute_data, return_value| {
                 let mut span = span.downcast::<Span>().unwrap();
+                let execute_data = execute_data.context("execute data is empty")?;
 
                 let ch = execute_data.get_parameter(0);
                 let result =
@@ -186,6 +233,7 @@ impl CurlPlugin {
                     .and_then(|code| code.as_long())
                     .context("Call curl_getinfo, http_code is null")?;
                 span.add_tag("status_code", &*http_code.to_string());
+                let header_size = response.get("header_size").and_then(|size| size.as_long());
                 if http_code == 0 {
                     let result =
                         call("curl_error", &mut [ch.clone()]).context("Call curl_get_info")?;
@@ -195,7 +243,7 @@ impl CurlPlugin {
                         .to_str()?;
                     span.with_span_object_mut(|span| {
                         span.is_error = true;
-                        span.add_log(vec![("CURL_ERROR", curl_error)]);
+                        span.add_redacted_log(vec![("CURL_ERROR", curl_error)]);
                     });
                 } else if http_code >= 400 {
                     span.with_span_object_mut(|span| span.is_error = true);
@@ -203,6 +251,13 @@ impl CurlPlugin {
                     span.with_span_object_mut(|span| span.is_error = false);
                 }
 
+                let cid = Self::get_resource_id(execute_data)?;
+                if CURL_HEADER_OUTPUTS.with(|outputs| outputs.borrow().contains(&cid)) {
+                    if let (Some(output), Some(size)) = (return_value.as_z_str(), header_size) {
+                        add_response_header_tags(&mut span, output.to_bytes(), size);
+                    }
+                }
+
                 Ok(())
             }),
         )
@@ -216,6 +271,7 @@ impl CurlPlugin {
                 let cid = Self::get_resource_id(execute_data)?;
 
                 CURL_HEADERS.with(|headers| headers.borrow_mut().remove(&cid));
+                CURL_HEADER_OUTPUTS.with(|outputs| outputs.borrow_mut().remove(&cid));
 
                 Ok(Box::new(()))
             }),
@@ -223,6 +279,22 @@ impl CurlPlugin {
         )
     }
 
+    /// Only track `CURLOPT_HEADER` if the response headers should be captured.
+    fn set_header_output(cid: i64, value: &ZVal) {
+        if CURL_RESPONSE_HEADERS.is_empty() {
+            return;
+        }
+        let enabled = value.as_bool().or_else(|| value.as_long().map(|n| n != 0));
+        CURL_HEADER_OUTPUTS.with(|outputs| {
+            let mut outputs = outputs.borrow_mut();
+            if enabled == Some(true) {
+                outputs.insert(cid);
+            } else {
+                outputs.remove(&cid);
+            }
+        });
+    }
+
     fn get_resource_id(execute_data: &mut ExecuteData) -> anyhow::Result<i64> {
         execute_data
             .get_parameter(0)
@@ -231,3 +303,21 @@ impl CurlPlugin {
             .context("Get resource id failed")
     }
 }
+
+/// Add the tags `http.response.headers.{name}` by the headers in the output,
+/// the last header block is used if redirected.
+fn add_response_header_tags(span: &mut Span, output: &[u8], header_size: i64) {
+    let header_size = (header_size.max(0) as usize).min(output.len());
+    let headers = String::from_utf8_lossy(&output[..header_size]);
+    let block = headers
+        .split("\r\n\r\n")
+        .filter(|block| !block.trim().is_empty())
+        .last()
+        .unwrap_or_default();
+    add_header_line_tags(
+        span,
+        "http.response.headers.",
+        &CURL_RESPONSE_HEADERS,
+        block.lines().skip(1),
+    );
+}
@@ -0,0 +1,191 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for the methods and functions configured by
+//! `skywalking_agent.custom_methods`, each one is traced in a local span.
+//!
+//! The entries are separated by `;` or new line, and the fields of entry are
+//! separated by `|`:
+//!
+//! ```ini
+//! skywalking_agent.custom_methods = "App\Billing\Invoice::render|Invoice/{arg}|0; my_function"
+//! ```
+//!
+//! 1. The `Class::method` or `function` to trace, required.
+//! 2. The operation name template, optional, the placeholders `{class}`,
+//!    `{function}` and `{arg}` (the captured argument) are replaced, default
+//!    is `Class->method` or `function`.
+//! 3. The index of argument to capture as tag `arg{index}`, optional.
+
+use super::{after_hook_with_exception, create_local_span, Plugin};
+use crate::{
+    execute::{AfterExecuteHook, BeforeExecuteHook},
+    redact::RedactedTag,
+    util::z_val_to_string,
+    SKYWALKING_AGENT_CUSTOM_METHODS,
+};
+use once_cell::sync::Lazy;
+use phper::{ini::Ini, values::ZVal};
+use tracing::warn;
+
+static METHODS: Lazy<Vec<CustomMethod>> = Lazy::new(|| {
+    let config = Ini::get::<String>(SKYWALKING_AGENT_CUSTOM_METHODS).unwrap_or_default();
+    config
+        .split(|c| c == ';' || c == '\n')
+        .filter_map(CustomMethod::parse)
+        .collect()
+});
+
+/// The class names (`None` for functions) and the function names of the
+/// custom methods.
+pub(super) fn custom_targets() -> impl Iterator<Item = (Option<&'static str>, &'static str)> {
+    METHODS
+        .iter()
+        .map(|method| (method.class_name.as_deref(), &*method.function_name))
+}
+
+#[derive(Clone)]
+struct CustomMethod {
+    class_name: Option<String>,
+    function_name: String,
+    operation_name: Option<String>,
+    arg_index: Option<usize>,
+}
+
+impl CustomMethod {
+    fn parse(entry: &str) -> Option<Self> {
+        let mut fields = entry.split('|').map(str::trim);
+
+        let target = fields.next().filter(|target| !target.is_empty())?;
+        let (class_name, function_name) = match target.split_once("::") {
+            Some((class_name, function_name)) => (
+                Some(class_name.trim_start_matches('\\').to_owned()),
+                function_name.to_owned(),
+            ),
+            None => (None, target.trim_start_matches('\\').to_owned()),
+        };
+
+        let operation_name = fields
+            .next()
+            .filter(|operation_name| !operation_name.is_empty())
+            .map(ToOwned::to_owned);
+
+        let arg_index = match fields.next().filter(|arg_index| !arg_index.is_empty()) {
+            Some(arg_index) => match arg_index.parse() {
+                Ok(arg_index) => Some(arg_index),
+                Err(_) => {
+                    warn!(entry, "invalid argument index of custom method");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Some(Self {
+            class_name,
+            function_name,
+            operation_name,
+            arg_index,
+        })
+    }
+
+    fn operation_name(&self, arg: Option<&str>) -> String {
+        match &self.operation_name {
+            Some(template) => template
+                .replace("{class}", self.class_name.as_deref().unwrap_or_default())
+                .replace("{function}", &self.function_name)
+                .replace("{arg}", arg.unwrap_or_default()),
+            None => match &self.class_name {
+                Some(class_name) => format!("{}->{}", class_name, self.function_name),
+                None => self.function_name.clone(),
+            },
+        }
+    }
+}
+
+pub struct CustomPlugin {
+    class_names: &'static [&'static str],
+    function_names: &'static [&'static str],
+}
+
+impl Default for CustomPlugin {
+    fn default() -> Self {
+        // Leaked, because the plugins live as long as the process.
+        let class_names = custom_targets()
+            .filter_map(|(class_name, _)| class_name)
+            .collect::<Vec<_>>();
+        let function_names = custom_targets()
+            .filter(|(class_name, _)| class_name.is_none())
+            .map(|(_, function_name)| function_name)
+            .collect::<Vec<_>>();
+
+        Self {
+            class_names: Box::leak(class_names.into_boxed_slice()),
+            function_names: Box::leak(function_names.into_boxed_slice()),
+        }
+    }
+}
+
+impl Plugin for CustomPlugin {
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        Some(self.class_names)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn function_names(&self) -> Option<&'static [&'static str]> {
+        Some(self.function_names)
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        let method = METHODS
+            .iter()
+            .find(|method| {
+                method.class_name.as_deref() == class_name
+                    && method.function_name.eq_ignore_ascii_case(function_name)
+            })?
+            .clone();
+
+        Some((
+            Box::new(move |execute_data| {
+                let arg = match method.arg_index {
+                    Some(arg_index) if arg_index < execute_data.num_args() => {
+                        z_val_to_tag_value(execute_data.get_parameter(arg_index))
+                    }
+                    _ => None,
+                };
+
+                let mut span = create_local_span(&method.operation_name(arg.as_deref()))?;
+                if let (Some(arg_index), Some(arg)) = (method.arg_index, &arg) {
+                    span.add_redacted_tag(&format!("arg{}", arg_index), arg);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        ))
+    }
+}
+
+/// Only the scalar argument can be captured.
+fn z_val_to_tag_value(zv: &ZVal) -> Option<String> {
+    z_val_to_string(zv)
+        .or_else(|| zv.as_long().map(|l| l.to_string()))
+        .or_else(|| zv.as_double().map(|d| d.to_string()))
+        .or_else(|| zv.as_bool().map(|b| b.to_string()))
+}
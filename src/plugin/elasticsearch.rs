@@ -0,0 +1,229 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [elasticsearch-php](https://github.com/elastic/elasticsearch-php) 7.x.
+
+use super::{mark_span_with_exception, Plugin};
+use crate::{
+    component::COMPONENT_ELASTICSEARCH_ID,
+    context::RequestContext,
+    execute::{validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    redact::RedactedTag,
+    util::z_val_to_string,
+    SKYWALKING_AGENT_ELASTICSEARCH_TRACE_DSL,
+};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use phper::{functions::call, ini::Ini, objects::ZObj};
+use skywalking::{context::span::Span, skywalking_proto::v3::SpanLayer};
+use std::cell::RefCell;
+use tracing::debug;
+
+static TRACE_DSL: Lazy<bool> =
+    Lazy::new(|| Ini::get::<bool>(SKYWALKING_AGENT_ELASTICSEARCH_TRACE_DSL).unwrap_or_default());
+
+const ENDPOINT_NAMESPACE: &str = "Elasticsearch\\Endpoints\\";
+
+/// The peer before the connection is selected by the pool.
+const UNKNOWN_PEER: &str = "unknown:9200";
+
+thread_local! {
+    /// The endpoints called by `Elasticsearch\Client::performRequest`, the
+    /// innermost one is requested by the transport.
+    static ENDPOINTS: RefCell<Vec<Endpoint>> = Default::default();
+
+    /// The spans of `Elasticsearch\Transport::performRequest`, the innermost
+    /// one gets the peer of the connection selected.
+    static TRANSPORT_SPANS: RefCell<Vec<Span>> = Default::default();
+}
+
+struct Endpoint {
+    name: String,
+    index: Option<String>,
+    body: Option<String>,
+}
+
+#[derive(Default, Clone)]
+pub struct ElasticsearchPlugin;
+
+impl Plugin for ElasticsearchPlugin {
+    fn name(&self) -> &'static str {
+        "elasticsearch"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_ELASTICSEARCH_ID]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["Elasticsearch\\Client", "Elasticsearch\\Transport"];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("Elasticsearch\\Client"), "performRequest") => {
+                Some(self.hook_client_perform_request())
+            }
+            (Some("Elasticsearch\\Transport"), "performRequest") => {
+                Some(self.hook_transport_perform_request())
+            }
+            (Some("Elasticsearch\\Transport"), "getConnection") => {
+                Some(self.hook_transport_get_connection())
+            }
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        ENDPOINTS.with(|endpoints| endpoints.borrow_mut().clear());
+        TRANSPORT_SPANS.with(|spans| spans.borrow_mut().clear());
+    }
+}
+
+impl ElasticsearchPlugin {
+    /// Hook `Elasticsearch\Client::performRequest(AbstractEndpoint
+    /// $endpoint)`, which is called by all api methods of client, save the
+    /// endpoint for the transport.
+    fn hook_client_perform_request(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let endpoint = execute_data
+                    .get_parameter(0)
+                    .as_z_obj()
+                    .context("endpoint isn't object")?;
+                let name = endpoint.get_class().get_name().to_str()?;
+                let name = name
+                    .strip_prefix(ENDPOINT_NAMESPACE)
+                    .unwrap_or(name)
+                    .replace('\\', ".");
+
+                debug!(name, "call elasticsearch endpoint");
+
+                let endpoint = Endpoint {
+                    name,
+                    index: z_val_to_string(endpoint.get_property("index")),
+                    body: if *TRACE_DSL { get_body(endpoint) } else { None },
+                };
+                ENDPOINTS.with(|endpoints| endpoints.borrow_mut().push(endpoint));
+
+                Ok(Box::new(()))
+            }),
+            Box::new(|_, _, _| {
+                ENDPOINTS.with(|endpoints| endpoints.borrow_mut().pop());
+                Ok(())
+            }),
+        )
+    }
+
+    /// Hook `Elasticsearch\Transport::performRequest($method, $uri, ...)`,
+    /// which is called again for the retries, the exit span is created for
+    /// each node requested.
+    fn hook_transport_perform_request(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let endpoint = ENDPOINTS.with(|endpoints| {
+                    endpoints.borrow().last().map(|endpoint| {
+                        (endpoint.name.clone(), endpoint.index.clone(), endpoint.body.clone())
+                    })
+                });
+                let (name, index, body) = match endpoint {
+                    Some(endpoint) => endpoint,
+                    None => {
+                        let method = z_val_to_string(execute_data.get_parameter(0))
+                            .context("method isn't string")?;
+                        (method, None, None)
+                    }
+                };
+
+                let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+                    Ok(ctx.create_exit_span(&format!("Elasticsearch/{}", name), UNKNOWN_PEER))
+                })?;
+
+                span.with_span_object_mut(|span| {
+                    span.set_span_layer(SpanLayer::Database);
+                    span.component_id = COMPONENT_ELASTICSEARCH_ID;
+                    span.add_tag("db.type", "Elasticsearch");
+                });
+                if let Some(index) = index {
+                    span.add_redacted_tag("es.indices", &index);
+                }
+                if let Some(body) = body {
+                    span.add_redacted_tag("db.statement", &body);
+                }
+
+                TRANSPORT_SPANS.with(|spans| spans.borrow_mut().push(span));
+
+                Ok(Box::new(()))
+            }),
+            Box::new(|_, _, _| {
+                let span = TRANSPORT_SPANS.with(|spans| spans.borrow_mut().pop());
+                match span {
+                    Some(mut span) => mark_span_with_exception(&mut span),
+                    None => Ok(()),
+                }
+            }),
+        )
+    }
+
+    /// Hook `Elasticsearch\Transport::getConnection()`, called by
+    /// `performRequest`, the peer of the span is the connection returned by
+    /// the pool.
+    fn hook_transport_get_connection(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Noop::noop(),
+            Box::new(|_, _, return_value| {
+                let connection = match return_value.as_mut_z_obj() {
+                    Some(connection) => connection,
+                    None => return Ok(()),
+                };
+                let peer = get_peer(connection)?;
+                TRANSPORT_SPANS.with(|spans| {
+                    if let Some(span) = spans.borrow_mut().last_mut() {
+                        span.with_span_object_mut(|span| span.peer = peer);
+                    }
+                });
+                Ok(())
+            }),
+        )
+    }
+}
+
+/// Get the peer of the connection, like `host:9200`.
+fn get_peer(connection: &mut ZObj) -> anyhow::Result<String> {
+    let host = connection.call("getHost", [])?;
+    let host = z_val_to_string(&host).context("host isn't string")?;
+    let port = connection.call("getPort", [])?;
+    Ok(format!("{}:{}", host, port.as_long().unwrap_or(9200)))
+}
+
+fn get_body(endpoint: &ZObj) -> Option<String> {
+    let body = endpoint.get_property("body");
+    if body.get_type_info().is_null() {
+        return None;
+    }
+    if let Some(body) = z_val_to_string(body) {
+        return Some(body);
+    }
+    call("json_encode", &mut [body.clone()])
+        .ok()
+        .as_ref()
+        .and_then(z_val_to_string)
+}
@@ -0,0 +1,157 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Prefix the messages of `error_log()` and the php error log with
+//! `[sw:TRACE_ID]`, enabled by `skywalking_agent.error_log_trace_id`.
+//!
+//! The messages of `error_log()` are also reported, if the log reporter is
+//! enabled.
+//!
+//! The messages passed to `error_log()` are modified by hook, and the others
+//! (such as the php warnings) are modified by the `log_message` of sapi, which
+//! is used when the ini `error_log` isn't set.
+
+use super::Plugin;
+use crate::{
+    context::RequestContext,
+    execute::{validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    log_reporter::{report_log, ERROR_LOG_LEVEL, LOG_REPORTER_ENABLE},
+    util::z_val_to_string,
+    SKYWALKING_AGENT_ERROR_LOG_TRACE_ID,
+};
+use once_cell::sync::Lazy;
+use phper::{ini::Ini, sys, values::ZVal};
+use std::{
+    ffi::{CStr, CString},
+    mem::transmute,
+    os::raw::{c_char, c_int},
+};
+
+/// The `$message_type` of `error_log()` which sends the message by email.
+const ERROR_LOG_MAIL: i64 = 1;
+
+static ERROR_LOG_TRACE_ID: Lazy<bool> =
+    Lazy::new(|| Ini::get::<bool>(SKYWALKING_AGENT_ERROR_LOG_TRACE_ID).unwrap_or_default());
+
+type LogMessage = unsafe extern "C" fn(message: *const c_char, syslog_type_int: c_int);
+
+static mut ORI_LOG_MESSAGE: Option<LogMessage> = None;
+
+#[derive(Default, Clone)]
+pub struct ErrorLogPlugin;
+
+impl Plugin for ErrorLogPlugin {
+    fn name(&self) -> &'static str {
+        "error_log"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        None
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn function_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["error_log"];
+        Some(if *ERROR_LOG_TRACE_ID || *LOG_REPORTER_ENABLE { NAMES } else { &[] })
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (None, "error_log") => Some(self.hook_error_log()),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorLogPlugin {
+    /// Hook `error_log($message, $message_type = 0, ...)`.
+    fn hook_error_log(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                if execute_data.num_args() >= 2
+                    && execute_data.get_parameter(1).as_long() == Some(ERROR_LOG_MAIL)
+                {
+                    return Ok(Box::new(()));
+                }
+
+                let message = execute_data.get_mut_parameter(0);
+                let m = match z_val_to_string(message) {
+                    Some(m) => m,
+                    None => return Ok(Box::new(())),
+                };
+
+                if *ERROR_LOG_TRACE_ID {
+                    if let Some(prefixed) = prefix_trace_id(&m) {
+                        *message = ZVal::from(prefixed);
+                    }
+                }
+
+                report_log(ERROR_LOG_LEVEL, m, vec![("logger", "error_log".to_owned())])?;
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+}
+
+/// Replace the `log_message` of sapi, should be called in module init.
+pub(crate) fn register_log_message() {
+    if !*ERROR_LOG_TRACE_ID {
+        return;
+    }
+    // The message is `char *` in php 7, and `const char *` in php 8, same abi.
+    unsafe {
+        if sys::sapi_module.log_message.is_some() {
+            ORI_LOG_MESSAGE = transmute(sys::sapi_module.log_message);
+            sys::sapi_module.log_message = transmute(Some(log_message as LogMessage));
+        }
+    }
+}
+
+unsafe extern "C" fn log_message(message: *const c_char, syslog_type_int: c_int) {
+    let ori_log_message = match ORI_LOG_MESSAGE {
+        Some(f) => f,
+        None => return,
+    };
+
+    let prefixed = CStr::from_ptr(message)
+        .to_str()
+        .ok()
+        .and_then(prefix_trace_id)
+        .and_then(|m| CString::new(m).ok());
+
+    match prefixed {
+        Some(m) => ori_log_message(m.as_ptr(), syslog_type_int),
+        None => ori_log_message(message, syslog_type_int),
+    }
+}
+
+/// Prefix the message with the trace id of the global request context, the
+/// prefixed message (by `error_log()` hook) isn't prefixed again.
+fn prefix_trace_id(message: &str) -> Option<String> {
+    if message.starts_with("[sw:") {
+        return None;
+    }
+    RequestContext::with_global(None, |ctx| {
+        format!("[sw:{}] {}", ctx.tracing_context.trace_id(), message)
+    })
+}
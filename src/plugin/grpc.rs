@@ -0,0 +1,205 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for the client of [grpc](https://github.com/grpc/grpc/tree/master/src/php).
+//!
+//! The exit span is created by `Grpc\BaseStub`, and finished when the status is
+//! received, by `Grpc\UnaryCall::wait` or `Grpc\ServerStreamingCall::getStatus`.
+
+use super::Plugin;
+use crate::{
+    component::COMPONENT_GRPC_ID,
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook},
+    propagation::inject_propagation,
+    redact::{RedactedLog, RedactedTag},
+    util::{copy_array, z_val_to_string},
+};
+use anyhow::Context;
+use phper::{
+    arrays::{InsertKey, ZArray},
+    values::ZVal,
+};
+use skywalking::{context::span::Span, skywalking_proto::v3::SpanLayer};
+use std::{cell::RefCell, collections::HashMap};
+use tracing::debug;
+
+/// Index of the `$metadata` parameter of `Grpc\BaseStub::_simpleRequest` and
+/// `Grpc\BaseStub::_serverStreamRequest`.
+const METADATA_INDEX: usize = 3;
+
+/// The status code `Grpc\STATUS_OK`.
+const STATUS_OK: i64 = 0;
+
+thread_local! {
+    /// Spans waiting for the status, keyed by the handle of call objects.
+    static CALL_SPANS: RefCell<HashMap<u32, Span>> = Default::default();
+}
+
+#[derive(Default, Clone)]
+pub struct GrpcPlugin;
+
+impl Plugin for GrpcPlugin {
+    fn name(&self) -> &'static str {
+        "grpc"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_GRPC_ID]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &[
+            "Grpc\\BaseStub",
+            "Grpc\\UnaryCall",
+            "Grpc\\ServerStreamingCall",
+        ];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("Grpc\\BaseStub"), "_simpleRequest" | "_serverStreamRequest") => {
+                Some(self.hook_base_stub_request())
+            }
+            (Some("Grpc\\UnaryCall"), "wait") => Some(self.hook_call_status(|return_value| {
+                return_value
+                    .as_z_arr()
+                    .and_then(|result| result.get(1))
+                    .cloned()
+            })),
+            (Some("Grpc\\ServerStreamingCall"), "getStatus") => {
+                Some(self.hook_call_status(|return_value| Some(return_value.clone())))
+            }
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        // The calls which status isn't waited, the spans are finished without it.
+        CALL_SPANS.with(|spans| spans.borrow_mut().clear());
+    }
+}
+
+impl GrpcPlugin {
+    fn hook_base_stub_request(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let this = get_this_mut(execute_data)?;
+                let target = this.call("getTarget", [])?;
+                let target = z_val_to_string(&target).context("target isn't string")?;
+                let peer = get_peer(&target);
+
+                let method = z_val_to_string(execute_data.get_parameter(0))
+                    .context("method isn't string")?;
+
+                debug!(method, peer, "call grpc method");
+
+                let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+                    Ok(ctx.create_exit_span(&method, &peer))
+                })?;
+
+                span.with_span_object_mut(|span| {
+                    span.set_span_layer(SpanLayer::RpcFramework);
+                    span.component_id = COMPONENT_GRPC_ID;
+                    span.add_redacted_tag("rpc.method", &method);
+                });
+
+                // The metadata argument is always passed by the generated stubs, the default
+                // value of user function can't be modified before executed.
+                if execute_data.num_args() > METADATA_INDEX {
+                    let headers = inject_propagation(None, &method, &peer)?;
+                    let metadata = execute_data.get_mut_parameter(METADATA_INDEX);
+                    inject_metadata(metadata, headers);
+                } else {
+                    debug!("grpc metadata argument is missing, skip inject");
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(|span, _, return_value| {
+                let span = *span.downcast::<Span>().unwrap();
+                if let Some(call) = return_value.as_z_obj() {
+                    CALL_SPANS.with(|spans| spans.borrow_mut().insert(call.handle(), span));
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    fn hook_call_status(
+        &self, get_status: fn(&ZVal) -> Option<ZVal>,
+    ) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let handle = get_this_mut(execute_data)?.handle();
+                let span = CALL_SPANS
+                    .with(|spans| spans.borrow_mut().remove(&handle))
+                    .context("call span not exists")?;
+                Ok(Box::new(span))
+            }),
+            Box::new(move |span, _, return_value| {
+                let mut span = span.downcast::<Span>().unwrap();
+
+                let status = get_status(return_value).context("status not exists")?;
+                let status = status.as_z_obj().context("status isn't object")?;
+                let code = status
+                    .get_property("code")
+                    .as_long()
+                    .context("status code isn't int")?;
+
+                span.add_tag("rpc.status_code", &code.to_string());
+                if code != STATUS_OK {
+                    let details =
+                        z_val_to_string(status.get_property("details")).unwrap_or_default();
+                    span.with_span_object_mut(|span| {
+                        span.is_error = true;
+                        span.add_redacted_log([
+                            ("Status Code", code.to_string()),
+                            ("Details", details),
+                        ]);
+                    });
+                }
+
+                Ok(())
+            }),
+        )
+    }
+}
+
+/// Add the propagation headers into the metadata, which values are array of
+/// string.
+fn inject_metadata(metadata: &mut ZVal, headers: Vec<(&'static str, String)>) {
+    let mut new_metadata = metadata.as_z_arr().map(copy_array).unwrap_or_else(ZArray::new);
+
+    for (name, value) in headers {
+        let mut values = ZArray::new();
+        values.insert(InsertKey::NextIndex, ZVal::from(value));
+        new_metadata.insert(InsertKey::Str(name), ZVal::from(values));
+    }
+
+    *metadata = ZVal::from(new_metadata);
+}
+
+/// Get the peer from target of channel, like `dns:///localhost:50051`.
+fn get_peer(target: &str) -> String {
+    match target.rsplit_once(":///") {
+        Some((_, address)) => address.to_owned(),
+        None => target.to_owned(),
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [laravel](https://laravel.com/).
+
+use super::{
+    after_hook_consumer, after_hook_with_exception, create_consumer_span, create_local_span,
+    Plugin,
+};
+use crate::{
+    component::COMPONENT_PHP_ID,
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook},
+    propagation::inject_propagation,
+    redact::RedactedTag,
+    util::{copy_array, z_val_to_string},
+};
+use anyhow::Context;
+use phper::{arrays::InsertKey, values::ZVal};
+use skywalking::skywalking_proto::v3::SpanLayer;
+use tracing::debug;
+
+#[derive(Default, Clone)]
+pub struct LaravelPlugin;
+
+impl Plugin for LaravelPlugin {
+    fn name(&self) -> &'static str {
+        "laravel"
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &[
+            "Illuminate\\Foundation\\Http\\Kernel",
+            "Illuminate\\Routing\\Router",
+            "Illuminate\\Routing\\ControllerDispatcher",
+            "Illuminate\\View\\View",
+            "Illuminate\\Bus\\Dispatcher",
+            "Illuminate\\Queue\\Queue",
+            "Illuminate\\Queue\\Worker",
+        ];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("Illuminate\\Foundation\\Http\\Kernel"), "sendRequestThroughRouter") => {
+                Some(self.hook_kernel_send_request())
+            }
+            (Some("Illuminate\\Routing\\Router"), "dispatch") => Some(self.hook_router_dispatch()),
+            (Some("Illuminate\\Routing\\Router"), "runRouteWithinStack") => {
+                Some(self.hook_router_run_route())
+            }
+            (Some("Illuminate\\Routing\\ControllerDispatcher"), "dispatch") => {
+                Some(self.hook_controller_dispatch())
+            }
+            (Some("Illuminate\\View\\View"), "render") => Some(self.hook_view_render()),
+            (Some("Illuminate\\Bus\\Dispatcher"), "dispatchToQueue") => {
+                Some(self.hook_dispatch_to_queue())
+            }
+            (Some("Illuminate\\Queue\\Queue"), "createPayloadArray") => {
+                Some(self.hook_queue_create_payload_array())
+            }
+            (Some("Illuminate\\Queue\\Worker"), "process") => Some(self.hook_worker_process()),
+            _ => None,
+        }
+    }
+}
+
+impl LaravelPlugin {
+    /// Hook
+    /// `Illuminate\Foundation\Http\Kernel::sendRequestThroughRouter($request)`,
+    /// the span covers the global middleware.
+    fn hook_kernel_send_request(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|_| {
+                let span = create_local_span(
+                    "Illuminate\\Foundation\\Http\\Kernel->sendRequestThroughRouter",
+                )?;
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    fn hook_router_dispatch(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|_| {
+                let span = create_local_span("Illuminate\\Routing\\Router->dispatch")?;
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    /// Hook `Illuminate\Routing\Router::runRouteWithinStack(Route $route,
+    /// Request $request)`, which is called after the route matched, the span
+    /// covers the route middleware and the controller.
+    fn hook_router_run_route(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let mut route = execute_data.get_parameter(0).clone();
+                let route = route.as_mut_z_obj().context("route isn't object")?;
+                let uri = route.call("uri", [])?;
+                let uri = z_val_to_string(&uri).context("route uri isn't string")?;
+                let uri = format!("/{}", uri.trim_start_matches('/'));
+
+                debug!(uri, "laravel route matched");
+
+                RequestContext::rename_entry_span_with_route(None, &uri)?;
+
+                let span = create_local_span("Illuminate\\Routing\\Router->runRouteWithinStack")?;
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    /// Hook `Illuminate\Routing\ControllerDispatcher::dispatch(Route $route,
+    /// $controller, $method)`.
+    fn hook_controller_dispatch(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 3)?;
+
+                let controller = execute_data
+                    .get_parameter(1)
+                    .as_z_obj()
+                    .context("controller isn't object")?;
+                let controller = controller.get_class().get_name().to_str()?.to_owned();
+                let method = z_val_to_string(execute_data.get_parameter(2))
+                    .context("method isn't string")?;
+
+                let span = create_local_span(&format!("{}->{}", controller, method))?;
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    fn hook_view_render(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let this = get_this_mut(execute_data)?;
+                let name = this.call("name", [])?;
+
+                let mut span = create_local_span("Illuminate\\View\\View->render")?;
+                if let Some(name) = z_val_to_string(&name) {
+                    span.add_redacted_tag("view.name", &name);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    /// Hook `Illuminate\Bus\Dispatcher::dispatchToQueue($command)`, the exit
+    /// span covers creating the payload and pushing it.
+    fn hook_dispatch_to_queue(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let command = execute_data
+                    .get_parameter(0)
+                    .as_z_obj()
+                    .context("command isn't object")?;
+                let job_name = command.get_class().get_name().to_str()?.to_owned();
+                let connection = z_val_to_string(command.get_property("connection"))
+                    .unwrap_or_else(|| "default".to_owned());
+                let queue = z_val_to_string(command.get_property("queue"));
+
+                let operation_name = format!("Laravel/Queue/{}/Producer", job_name);
+                let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+                    Ok(ctx.create_exit_span(&operation_name, &connection))
+                })?;
+
+                span.with_span_object_mut(|span| {
+                    span.set_span_layer(SpanLayer::Mq);
+                    span.component_id = COMPONENT_PHP_ID;
+                });
+                span.add_redacted_tag("mq.broker", &connection);
+                if let Some(queue) = &queue {
+                    span.add_redacted_tag("mq.queue", queue);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    /// Hook `Illuminate\Queue\Queue::createPayloadArray($job, $queue, $data)`,
+    /// add the propagation headers into the payload array before it is encoded,
+    /// so the worker can continue the trace.
+    fn hook_queue_create_payload_array(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let queue = if execute_data.num_args() >= 2 {
+                    z_val_to_string(execute_data.get_parameter(1))
+                } else {
+                    None
+                };
+                let queue = queue.unwrap_or_else(|| "default".to_owned());
+                let connection =
+                    z_val_to_string(get_this_mut(execute_data)?.get_property("connectionName"))
+                        .unwrap_or_else(|| "default".to_owned());
+
+                let headers =
+                    inject_propagation(None, &format!("Laravel/Queue/{}", queue), &connection)?;
+
+                Ok(Box::new(headers))
+            }),
+            Box::new(|headers, _, return_value| {
+                let headers = *headers.downcast::<Vec<(&'static str, String)>>().unwrap();
+
+                let payload = return_value.as_z_arr().context("payload isn't array")?;
+                let mut new_payload = copy_array(payload);
+                for (name, value) in headers {
+                    new_payload.insert(InsertKey::Str(name), ZVal::from(value));
+                }
+                *return_value = ZVal::from(new_payload);
+
+                Ok(())
+            }),
+        )
+    }
+
+    /// Hook `Illuminate\Queue\Worker::process($connectionName, $job,
+    /// WorkerOptions $options)`, the job is processed in an entry span of a
+    /// new trace context, which continue the trace of the payload headers.
+    fn hook_worker_process(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+
+                let connection_name =
+                    z_val_to_string(execute_data.get_parameter(0)).unwrap_or_default();
+                let mut job = execute_data.get_parameter(1).clone();
+                let job = job.as_mut_z_obj().context("job isn't object")?;
+
+                let job_name = job.call("resolveName", [])?;
+                let job_name = z_val_to_string(&job_name).unwrap_or_default();
+                let queue = job.call("getQueue", [])?;
+                let queue = z_val_to_string(&queue).unwrap_or_default();
+                let payload = job.call("payload", [])?;
+                let payload = payload.as_z_arr();
+                let get_header = |name: &str| payload?.get(name).and_then(z_val_to_string);
+
+                let span = create_consumer_span(
+                    &format!("Laravel/Queue/{}", job_name),
+                    get_header,
+                    |span| {
+                        span.set_span_layer(SpanLayer::Mq);
+                        span.component_id = COMPONENT_PHP_ID;
+                        span.peer = connection_name.clone();
+                        span.add_redacted_tag("mq.broker", &connection_name);
+                        span.add_redacted_tag("mq.queue", &queue);
+                    },
+                )?;
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_consumer),
+        )
+    }
+}
@@ -8,51 +8,539 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod codeigniter;
 mod curl;
+mod custom;
+mod elasticsearch;
+mod error_log;
+mod grpc;
+mod laravel;
+mod monolog;
 mod pdo;
+mod pgsql;
+mod rdkafka;
+mod sql;
+mod sqlite;
+mod symfony;
+mod thinkphp;
+mod yar;
+mod yii;
 
-use crate::execute::{AfterExecuteHook, BeforeExecuteHook};
+pub(crate) use error_log::register_log_message;
+
+use crate::{
+    cds::is_plugin_disabled,
+    component::COMPONENT_PHP_ID,
+    context::RequestContext,
+    execute::{get_exception, AfterExecuteHook, BeforeExecuteHook},
+    propagation::{extract_propagation, extract_segment_ref, ExtractedContext},
+    redact::{split_names, RedactedLog},
+    util::z_val_to_string,
+    SKYWALKING_AGENT_PLUGINS_DISABLED,
+};
+use anyhow::Context;
 use once_cell::sync::Lazy;
+use phper::{
+    objects::ZObj,
+    strings::ZStr,
+    sys,
+    values::{ExecuteData, ZVal},
+};
+use skywalking::{context::span::Span, skywalking_proto::v3::SpanObject};
+use std::{any::Any, cell::RefCell, collections::HashMap, iter, rc::Rc};
+use tracing::warn;
 
 // Register plugins here.
 static PLUGINS: Lazy<Vec<Box<DynPlugin>>> = Lazy::new(|| {
     vec![
         Box::new(curl::CurlPlugin::default()),
         Box::new(pdo::PdoPlugin::default()),
+        Box::new(sqlite::SqlitePlugin::default()),
+        Box::new(pgsql::PgsqlPlugin::default()),
+        Box::new(rdkafka::RdKafkaPlugin::default()),
+        Box::new(elasticsearch::ElasticsearchPlugin::default()),
+        Box::new(grpc::GrpcPlugin::default()),
+        Box::new(yar::YarPlugin::default()),
+        Box::new(laravel::LaravelPlugin::default()),
+        Box::new(symfony::SymfonyPlugin::default()),
+        Box::new(thinkphp::ThinkPhpPlugin::default()),
+        Box::new(yii::YiiPlugin::default()),
+        Box::new(codeigniter::CodeIgniterPlugin::default()),
+        Box::new(monolog::MonologPlugin::default()),
+        Box::new(error_log::ErrorLogPlugin::default()),
+        Box::new(custom::CustomPlugin::default()),
     ]
 });
 
 pub type DynPlugin = dyn Plugin + Send + Sync + 'static;
 
 pub trait Plugin {
+    /// The lowercase name to disable the plugin, like `curl`.
+    fn name(&self) -> &'static str;
+
+    /// The component ids of the spans created by the plugin, shown in
+    /// `phpinfo()`, the framework plugins create the local spans of `php`.
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_PHP_ID]
+    }
+
     fn class_names(&self) -> Option<&'static [&'static str]>;
 
     fn function_name_prefix(&self) -> Option<&'static str>;
 
+    /// The exact function names, for the plugins which can't be matched by
+    /// prefix.
+    fn function_names(&self) -> Option<&'static [&'static str]> {
+        None
+    }
+
     fn hook(
         &self, class_name: Option<&str>, function_name: &str,
     ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)>;
+
+    /// Clear the states of the current request, like the ones keyed by the
+    /// object handles, which are reused by the next request.
+    fn clear(&self) {}
+}
+
+/// Clear the states of all plugins, called in request shutdown, even if the
+/// request is skipped.
+pub fn clear_plugins() {
+    for plugin in plugins() {
+        plugin.clear();
+    }
+    REQUEST_HOOK_INDEX.with(|index| index.borrow_mut().clear());
+}
+
+/// The plugins disabled by `skywalking_agent.plugins.disabled`.
+static DISABLED_PLUGINS: Lazy<Vec<String>> =
+    Lazy::new(|| split_names(SKYWALKING_AGENT_PLUGINS_DISABLED));
+
+/// Get all registered plugins, including the disabled ones.
+pub fn plugins() -> impl Iterator<Item = &'static DynPlugin> {
+    PLUGINS.iter().map(AsRef::as_ref)
+}
+
+fn is_disabled_by_ini(plugin: &DynPlugin) -> bool {
+    DISABLED_PLUGINS.iter().any(|disabled| disabled == plugin.name())
+}
+
+/// Whether the plugin is enabled, by the ini and the dynamic configuration.
+pub fn is_plugin_enabled(plugin: &DynPlugin) -> bool {
+    !is_disabled_by_ini(plugin) && !is_plugin_disabled(plugin.name())
+}
+
+/// The plugin hooking the function, with the names passed to [Plugin::hook] and
+/// the hooks returned.
+#[derive(Clone)]
+pub struct HookTarget {
+    pub plugin: &'static DynPlugin,
+    /// The class name declared by the plugin, the called class may be its
+    /// subclass.
+    pub class_name: Option<&'static str>,
+    /// The function name declared by the class (or the function itself).
+    pub function_name: Rc<str>,
+    /// The called method overrides the hooked one of the ancestor class.
+    pub is_inherited: bool,
+    /// Created once when the function is indexed, and called by all the calls.
+    pub hooks: Rc<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)>,
+}
+
+impl HookTarget {
+    pub fn is_same_hook(&self, other: &HookTarget) -> bool {
+        self.class_name == other.class_name && self.function_name == other.function_name
+    }
+}
+
+/// The hook targets keyed by the functions, `None` for the unhooked ones.
+type HookIndex = HashMap<*const sys::zend_function, Option<HookTarget>>;
+
+thread_local! {
+    /// The internal functions, which live as long as the process, so the index
+    /// is kept across requests.
+    static INTERNAL_HOOK_INDEX: RefCell<HookIndex> = Default::default();
+
+    /// The user functions and the internal methods copied into the user
+    /// classes, which are freed in request shutdown and their addresses are
+    /// reused by the next request, so the index is cleared by [clear_plugins].
+    static REQUEST_HOOK_INDEX: RefCell<HookIndex> = Default::default();
+}
+
+/// Select the hook target of the executing function by the index, built lazily
+/// when the function is called at the first time, so the later calls are only
+/// looked up by the address, without decoding the names.
+///
+/// The plugins disabled by the dynamic configuration should be checked by the
+/// caller.
+///
+/// # Safety
+///
+/// The function should be the executing one, or null.
+pub unsafe fn select_hook_target(function: *const sys::zend_function) -> Option<HookTarget> {
+    let function_ref = function.as_ref()?;
+
+    // The main script, include and eval haven't function name, and the op array
+    // of them may be freed before request shutdown.
+    if function_ref.common.function_name.is_null() {
+        return None;
+    }
+
+    // The trampolines of `__call` are shared by the methods called, and the
+    // closures are copied into the closure objects, so they can't be keyed by the
+    // address.
+    let fn_flags = function_ref.common.fn_flags;
+    if fn_flags & (sys::ZEND_ACC_CALL_VIA_TRAMPOLINE | sys::ZEND_ACC_CLOSURE) != 0 {
+        return match get_function_name(function_ref)? {
+            "{closure}" => None,
+            _ => resolve_function_hook_target(function_ref),
+        };
+    }
+
+    let index = if is_user_function(function_ref) || fn_flags & sys::ZEND_ACC_ARENA_ALLOCATED != 0 {
+        &REQUEST_HOOK_INDEX
+    } else {
+        &INTERNAL_HOOK_INDEX
+    };
+
+    if let Some(target) = index.with(|index| index.borrow().get(&function).cloned()) {
+        return target;
+    }
+
+    // Resolved without borrowing the index, because the php functions are
+    // called, which are hooked too.
+    let target = resolve_function_hook_target(function_ref);
+    index.with(|index| index.borrow_mut().insert(function, target.clone()));
+    target
+}
+
+unsafe fn get_function_name(function: &sys::zend_function) -> Option<&'static str> {
+    if function.common.function_name.is_null() {
+        return None;
+    }
+    ZStr::from_ptr(function.common.function_name).to_str().ok()
+}
+
+/// Resolve the hook target of the function by its name and scope.
+unsafe fn resolve_function_hook_target(function: &sys::zend_function) -> Option<HookTarget> {
+    resolve_hook_target(
+        function.common.scope.as_ref(),
+        get_function_name(function)?,
+        is_user_function(function),
+    )
+}
+
+fn is_user_function(function: &sys::zend_function) -> bool {
+    unsafe { function.type_ as u32 == sys::ZEND_USER_FUNCTION }
+}
+
+/// Resolve the hook target by the scope class and its ancestors, the names are
+/// matched case insensitively.
+fn resolve_hook_target(
+    scope: Option<&sys::zend_class_entry>, function_name: &str, is_user_function: bool,
+) -> Option<HookTarget> {
+    let class_name = match scope {
+        Some(scope) => Some(get_class_name(scope)?),
+        None => None,
+    };
+
+    if let Some((plugin, plugin_class_name, hooks)) = select_plugin(class_name, function_name) {
+        return Some(HookTarget {
+            plugin,
+            class_name: plugin_class_name,
+            function_name: function_name.into(),
+            is_inherited: false,
+            hooks: Rc::new(hooks),
+        });
+    }
+
+    for class_entry in iter::successors(get_parent_class(scope?), |c| get_parent_class(c)) {
+        let parent = match get_class_name(class_entry) {
+            Some(parent) => parent,
+            None => continue,
+        };
+        let function_name = match find_method_name(class_entry, function_name) {
+            Some(function_name) => function_name,
+            None => continue,
+        };
+        let (plugin, plugin_class_name, hooks) = match select_plugin(Some(parent), &function_name)
+        {
+            Some((plugin, Some(plugin_class_name), hooks)) => (plugin, plugin_class_name, hooks),
+            _ => continue,
+        };
+
+        // The hooks of the internal classes (like `PDO`) use `$this` in the after
+        // hooks, which has been released for the user functions, so only the
+        // `parent::` calls of the overriding methods are hooked.
+        if is_user_function && class_entry.type_ as u32 == sys::ZEND_INTERNAL_CLASS {
+            return None;
+        }
+
+        return Some(HookTarget {
+            plugin,
+            class_name: Some(plugin_class_name),
+            function_name,
+            is_inherited: true,
+            hooks: Rc::new(hooks),
+        });
+    }
+
+    None
 }
 
-pub fn select_plugin(class_name: Option<&str>, function_name: &str) -> Option<&'static DynPlugin> {
-    let mut selected_plugin = None;
+/// Select the first plugin hooking the function, the names are matched case
+/// insensitively, with the class name declared by the plugin and the hooks.
+///
+/// The plugins matching the names but not hooking the function are skipped,
+/// so the custom methods can be added into the classes of other plugins.
+fn select_plugin(
+    class_name: Option<&str>, function_name: &str,
+) -> Option<(
+    &'static DynPlugin,
+    Option<&'static str>,
+    (Box<BeforeExecuteHook>, Box<AfterExecuteHook>),
+)> {
+    select_plugins(class_name, function_name).find_map(|(plugin, plugin_class_name)| {
+        let hooks = plugin.hook(plugin_class_name, function_name)?;
+        Some((plugin, plugin_class_name, hooks))
+    })
+}
 
-    for plugin in &*PLUGINS {
-        if let Some(class_name) = class_name {
-            if let Some(plugin_class_names) = plugin.class_names() {
-                if plugin_class_names.contains(&class_name) {
-                    selected_plugin = Some(plugin);
-                    break;
+/// Select the plugins matching the names, in the registered order.
+fn select_plugins<'a>(
+    class_name: Option<&'a str>, function_name: &'a str,
+) -> impl Iterator<Item = (&'static DynPlugin, Option<&'static str>)> + 'a {
+    PLUGINS
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|plugin| !is_disabled_by_ini(*plugin))
+        .filter_map(move |plugin| {
+            if let Some(class_name) = class_name {
+                if let Some(plugin_class_names) = plugin.class_names() {
+                    if let Some(plugin_class_name) = plugin_class_names
+                        .iter()
+                        .find(|name| name.eq_ignore_ascii_case(class_name))
+                    {
+                        return Some((plugin, Some(*plugin_class_name)));
+                    }
                 }
             }
-        }
-        if let Some(function_name_prefix) = plugin.function_name_prefix() {
-            if function_name.starts_with(function_name_prefix) {
-                selected_plugin = Some(plugin);
-                break;
+            if class_name.is_none() {
+                if let Some(plugin_function_names) = plugin.function_names() {
+                    if plugin_function_names
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(function_name))
+                    {
+                        return Some((plugin, None));
+                    }
+                }
+            }
+            if let Some(function_name_prefix) = plugin.function_name_prefix() {
+                if function_name
+                    .get(..function_name_prefix.len())
+                    .map_or(false, |prefix| prefix.eq_ignore_ascii_case(function_name_prefix))
+                {
+                    return Some((plugin, None));
+                }
             }
+            None
+        })
+}
+
+/// Warn the custom methods hooked by the builtin plugins already, which are
+/// ignored, called in module init.
+pub fn warn_shadowed_custom_methods() {
+    for (class_name, function_name) in custom::custom_targets() {
+        let shadowed_by = select_plugins(class_name, function_name)
+            .take_while(|(plugin, _)| plugin.name() != "custom")
+            .find(|(plugin, plugin_class_name)| {
+                plugin.hook(*plugin_class_name, function_name).is_some()
+            });
+        if let Some((plugin, _)) = shadowed_by {
+            warn!(
+                class_name,
+                function_name,
+                plugin = plugin.name(),
+                "custom method is ignored, because it is hooked by the builtin plugin"
+            );
+        }
+    }
+}
+
+/// Get the parent of the linked class.
+///
+/// Since php 7.4 the `parent` is the union with `parent_name`, which holds the
+/// pointer once the class is linked, as the classes whose methods are called.
+fn get_parent_class(class_entry: &sys::zend_class_entry) -> Option<&'static sys::zend_class_entry> {
+    #[cfg(all(
+        phper_major_version = "7",
+        any(
+            phper_minor_version = "0",
+            phper_minor_version = "1",
+            phper_minor_version = "2",
+            phper_minor_version = "3"
+        )
+    ))]
+    let parent = class_entry.parent;
+
+    #[cfg(not(all(
+        phper_major_version = "7",
+        any(
+            phper_minor_version = "0",
+            phper_minor_version = "1",
+            phper_minor_version = "2",
+            phper_minor_version = "3"
+        )
+    )))]
+    let parent = unsafe { class_entry.__bindgen_anon_1.parent };
+
+    unsafe { parent.as_ref() }
+}
+
+fn get_class_name(class_entry: &sys::zend_class_entry) -> Option<&'static str> {
+    if class_entry.name.is_null() {
+        return None;
+    }
+    unsafe { ZStr::from_ptr(class_entry.name).to_str().ok() }
+}
+
+/// Whether the class is the one named or its subclass, like `instanceof` but
+/// without the interfaces, the names are matched case insensitively.
+pub(crate) fn is_class_or_subclass(class_entry: &sys::zend_class_entry, class_name: &str) -> bool {
+    iter::successors(Some(class_entry), |c| get_parent_class(c))
+        .filter_map(get_class_name)
+        .any(|name| name.eq_ignore_ascii_case(class_name))
+}
+
+/// Get the declared name of the method, found by the lowercase name.
+fn find_method_name(class_entry: &sys::zend_class_entry, function_name: &str) -> Option<Rc<str>> {
+    let name = function_name.to_ascii_lowercase();
+    unsafe {
+        let function = sys::zend_hash_str_find(
+            &class_entry.function_table,
+            name.as_ptr().cast(),
+            name.len() as sys::size_t,
+        );
+        let function = (function.as_ref()?.value.ptr as *const sys::zend_function).as_ref()?;
+        let function_name = function.common.function_name;
+        if function_name.is_null() {
+            return None;
         }
+        ZStr::from_ptr(function_name).to_str().ok().map(Into::into)
+    }
+}
+
+/// Create the local span for the framework plugins.
+pub(crate) fn create_local_span(operation_name: &str) -> anyhow::Result<Span> {
+    let mut span =
+        RequestContext::try_with_global_ctx(None, |ctx| Ok(ctx.create_local_span(operation_name)))?;
+    span.with_span_object_mut(|span| span.component_id = COMPONENT_PHP_ID);
+    Ok(span)
+}
+
+/// The after hook to mark the span as error if exception is thrown.
+pub(crate) fn after_hook_with_exception(
+    span: Box<dyn Any>, _execute_data: Option<&mut ExecuteData>, _return_value: &mut ZVal,
+) -> anyhow::Result<()> {
+    let mut span = span.downcast::<Span>().unwrap();
+    mark_span_with_exception(&mut span)
+}
+
+/// Mark the span as error and log the exception if exception is thrown.
+pub(crate) fn mark_span_with_exception(span: &mut Span) -> anyhow::Result<()> {
+    match get_exception() {
+        Some(e) => mark_span_with_caught_exception(span, e),
+        None => Ok(()),
+    }
+}
+
+/// Mark the span as error and log the exception, which is caught and passed
+/// to the handlers, so isn't the thrown one.
+pub(crate) fn mark_span_with_caught_exception(span: &mut Span, e: &ZObj) -> anyhow::Result<()> {
+    let class_name = e.get_class().get_name().to_str()?.to_owned();
+    let message = z_val_to_string(e.get_property("message")).unwrap_or_default();
+    span.with_span_object_mut(|span| {
+        span.is_error = true;
+        span.add_redacted_log([("Exception", class_name), ("Message", message)]);
+    });
+    Ok(())
+}
+
+/// Create the consumer span of message queue, which continue the trace of the
+/// propagation headers, `get_header` gets the value by the lowercase header
+/// name.
+///
+/// If consumed in a traced request, the local span referencing the upstream
+/// segment is created and returned, otherwise (such as in cli mode), the entry
+/// span of a new trace context is created and set as the global request
+/// context, which should be finished by [finish_consumer_context].
+pub(crate) fn create_consumer_span(
+    operation_name: &str, get_header: impl Fn(&str) -> Option<String>,
+    f: impl FnOnce(&mut SpanObject),
+) -> anyhow::Result<Option<Span>> {
+    if RequestContext::with_global(None, |_| ()).is_some() {
+        let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+            Ok(ctx.create_local_span(operation_name))
+        })?;
+        let segment_ref = extract_segment_ref(get_header);
+        span.with_span_object_mut(|span| {
+            span.refs.extend(segment_ref);
+            f(span);
+        });
+        return Ok(Some(span));
     }
 
-    selected_plugin.map(AsRef::as_ref)
+    let mut extracted = extract_propagation(get_header);
+    let mut span = extracted.create_entry_span(operation_name);
+    span.with_span_object_mut(f);
+
+    let ExtractedContext {
+        tracing_context: ctx,
+        correlation,
+        extension,
+        trace_state,
+        sampled,
+        ..
+    } = extracted;
+
+    RequestContext::set_global(
+        None,
+        RequestContext {
+            tracing_context: ctx,
+            entry_span: span,
+            correlation,
+            extension,
+            trace_state,
+            sampled,
+            log_count: 0,
+            span_count: 0,
+        },
+    );
+
+    Ok(None)
+}
+
+/// Finish the global request context created by [create_consumer_span].
+pub(crate) fn finish_consumer_context() -> anyhow::Result<()> {
+    let RequestContext {
+        tracing_context,
+        mut entry_span,
+        ..
+    } = RequestContext::remove_global(None).context("request context not exists")?;
+
+    mark_span_with_exception(&mut entry_span)?;
+
+    drop(entry_span);
+    drop(tracing_context);
+
+    Ok(())
+}
+
+/// The after hook paired with [create_consumer_span] in before hook.
+pub(crate) fn after_hook_consumer(
+    span: Box<dyn Any>, _execute_data: Option<&mut ExecuteData>, _return_value: &mut ZVal,
+) -> anyhow::Result<()> {
+    match *span.downcast::<Option<Span>>().unwrap() {
+        Some(mut span) => mark_span_with_exception(&mut span),
+        None => finish_consumer_context(),
+    }
 }
@@ -0,0 +1,153 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [monolog](https://github.com/Seldaek/monolog), add the
+//! `trace_id`, `segment_id` and `span_id` into the `extra` of records, and
+//! report the records if the log reporter is enabled.
+
+use super::Plugin;
+use crate::{
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    log_reporter::report_log,
+    util::{copy_array, z_val_to_string},
+};
+use anyhow::Context;
+use phper::{
+    arrays::{InsertKey, ZArr, ZArray},
+    values::ZVal,
+};
+use tracing::warn;
+
+#[derive(Default, Clone)]
+pub struct MonologPlugin;
+
+impl Plugin for MonologPlugin {
+    fn name(&self) -> &'static str {
+        "monolog"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &[
+            "Monolog\\Logger",
+            "Monolog\\Handler\\AbstractProcessingHandler",
+        ];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("Monolog\\Logger"), "addRecord") => Some(self.hook_logger_add_record()),
+            (Some("Monolog\\Handler\\AbstractProcessingHandler"), "handle") => {
+                Some(self.hook_handler_handle())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MonologPlugin {
+    /// Hook `Monolog\Logger::addRecord($level, $message, ...)`, report the
+    /// record.
+    fn hook_logger_add_record(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                if RequestContext::with_global(None, |_| ()).is_none() {
+                    return Ok(Box::new(()));
+                }
+
+                validate_num_args(execute_data, 2)?;
+
+                // The level is int, or the `Level` enum of monolog 3.
+                let level = execute_data.get_parameter(0);
+                let level = level.as_long().or_else(|| {
+                    level
+                        .as_z_obj()
+                        .and_then(|level| level.get_property("value").as_long())
+                });
+                let message = z_val_to_string(execute_data.get_parameter(1));
+
+                let this = get_this_mut(execute_data)?;
+
+                if let (Some(level), Some(message)) = (level, message) {
+                    let name = z_val_to_string(this.get_property("name")).unwrap_or_default();
+                    if let Err(err) = report_log(level, message, vec![("logger", name)]) {
+                        warn!(?err, "report monolog record failed");
+                    }
+                }
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    /// Hook `Monolog\Handler\AbstractProcessingHandler::handle($record)`, add the
+    /// ids into the `extra` of the record before processed and written, the
+    /// record is created inside `Logger::addRecord`, so it can't be modified
+    /// there.
+    ///
+    /// The record is an array, or the `LogRecord` of monolog 3, which only
+    /// `extra` can be set.
+    fn hook_handler_handle(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let ids = RequestContext::with_global(None, |ctx| {
+                    let ctx = &ctx.tracing_context;
+                    let span_id = ctx.peek_active_span_id().map(|span_id| span_id as i64);
+                    vec![
+                        ("trace_id", ZVal::from(ctx.trace_id())),
+                        ("segment_id", ZVal::from(ctx.trace_segment_id())),
+                        ("span_id", span_id.map(ZVal::from).unwrap_or_else(|| ZVal::from(()))),
+                    ]
+                });
+                let ids = match ids {
+                    Some(ids) => ids,
+                    None => return Ok(Box::new(())),
+                };
+
+                validate_num_args(execute_data, 1)?;
+
+                let record = execute_data.get_mut_parameter(0);
+                if let Some(record) = record.as_mut_z_obj() {
+                    let extra = add_ids(record.get_property("extra").as_z_arr(), ids);
+                    record.set_property("extra", ZVal::from(extra));
+                } else {
+                    let record_arr = record.as_z_arr().context("record isn't array")?;
+                    let extra = add_ids(record_arr.get("extra").and_then(ZVal::as_z_arr), ids);
+                    let mut new_record = copy_array(record_arr);
+                    new_record.insert(InsertKey::Str("extra"), ZVal::from(extra));
+                    *record = ZVal::from(new_record);
+                }
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+}
+
+fn add_ids(extra: Option<&ZArr>, ids: Vec<(&'static str, ZVal)>) -> ZArray {
+    let mut extra = extra.map(copy_array).unwrap_or_else(ZArray::new);
+    for (key, value) in ids {
+        extra.insert(InsertKey::Str(key), value);
+    }
+    extra
+}
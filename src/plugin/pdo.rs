@@ -8,15 +8,20 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use super::Plugin;
+use super::{
+    is_class_or_subclass,
+    sql::{add_parameters_tag, add_statement_tag, format_parameter, PARAMETERS_ENABLE},
+    Plugin,
+};
 use crate::{
     component::COMPONENT_PHP_PDO_ID,
     context::RequestContext,
     execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    redact::{RedactedLog, RedactedTag},
 };
 use anyhow::Context;
 use phper::{
-    arrays::ZArr,
+    arrays::{IterKey, ZArr},
     objects::ZObj,
     sys,
     values::{ExecuteData, ZVal},
@@ -28,12 +33,29 @@ use tracing::{debug, warn};
 thread_local! {
     static DSN_MAP: RefCell<HashMap<u32, Dsn>> = Default::default();
     static DTOR_MAP: RefCell<HashMap<u32, sys::zend_object_dtor_obj_t>> = Default::default();
+    static PARAMETERS_MAP: RefCell<HashMap<u32, Vec<(ParameterKey, String)>>> = Default::default();
+}
+
+/// The key of the parameter bound by `PDOStatement::bindValue` or
+/// `PDOStatement::bindParam`.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum ParameterKey {
+    Position(i64),
+    Name(String),
 }
 
 #[derive(Default, Clone)]
 pub struct PdoPlugin;
 
 impl Plugin for PdoPlugin {
+    fn name(&self) -> &'static str {
+        "pdo"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_PHP_PDO_ID]
+    }
+
     fn class_names(&self) -> Option<&'static [&'static str]> {
         static NAMES: &[&str] = &["PDO", "PDOStatement"];
         Some(NAMES)
@@ -57,8 +79,8 @@ impl Plugin for PdoPlugin {
                     "query",
                     "prepare",
                     "commit",
-                    "begintransaction",
-                    "rollback",
+                    "beginTransaction",
+                    "rollBack",
                 ]
                 .contains(&f) =>
             {
@@ -69,9 +91,16 @@ impl Plugin for PdoPlugin {
             {
                 Some(self.hook_pdo_statement_methods(function_name))
             }
+            (Some("PDOStatement"), "bindValue" | "bindParam") if *PARAMETERS_ENABLE => {
+                Some(self.hook_pdo_statement_bind())
+            }
             _ => None,
         }
     }
+
+    fn clear(&self) {
+        PARAMETERS_MAP.with(|parameters_map| parameters_map.borrow_mut().clear());
+    }
 }
 
 impl PdoPlugin {
@@ -111,15 +140,23 @@ impl PdoPlugin {
 
                 debug!(handle, function_name, "call PDO method");
 
-                let mut span = with_dsn(handle, |dsn| {
-                    create_exit_span_with_dsn("PDO", &function_name, dsn)
-                })?;
-
-                if execute_data.num_args() >= 1 {
-                    if let Some(statement) = execute_data.get_parameter(0).as_z_str() {
-                        span.add_tag("db.statement", statement.to_str()?);
+                let statement = if execute_data.num_args() >= 1 {
+                    execute_data.get_parameter(0).as_z_str()
+                } else {
+                    None
+                };
+
+                let span = with_dsn(handle, |dsn| {
+                    let mut span = create_exit_span_with_dsn(
+                        &format!("PDO->{}", function_name),
+                        COMPONENT_PHP_PDO_ID,
+                        dsn,
+                    )?;
+                    if let Some(statement) = statement {
+                        add_statement_tag(&mut span, &dsn.db_type, statement.to_str()?);
                     }
-                }
+                    Ok(span)
+                })?;
 
                 Ok(Box::new(span) as _)
             }),
@@ -139,13 +176,21 @@ impl PdoPlugin {
                 debug!(handle, function_name, "call PDOStatement method");
 
                 let mut span = with_dsn(handle, |dsn| {
-                    create_exit_span_with_dsn("PDOStatement", &function_name, dsn)
+                    let mut span = create_exit_span_with_dsn(
+                        &format!("PDOStatement->{}", function_name),
+                        COMPONENT_PHP_PDO_ID,
+                        dsn,
+                    )?;
+                    if let Some(query) = this.get_property("queryString").as_z_str() {
+                        add_statement_tag(&mut span, &dsn.db_type, query.to_str()?);
+                    } else {
+                        warn!("PDOStatement queryString is empty");
+                    }
+                    Ok(span)
                 })?;
 
-                if let Some(query) = this.get_property("queryString").as_z_str() {
-                    span.add_tag("db.statement", query.to_str()?);
-                } else {
-                    warn!("PDOStatement queryString is empty");
+                if *PARAMETERS_ENABLE && function_name == "execute" {
+                    add_statement_parameters(handle, execute_data, &mut span);
                 }
 
                 Ok(Box::new(span) as _)
@@ -153,6 +198,68 @@ impl PdoPlugin {
             Box::new(after_hook),
         )
     }
+
+    /// Hook `PDOStatement::bindValue($param, $value, ...)` and
+    /// `PDOStatement::bindParam($param, &$var, ...)`, save the formatted
+    /// parameters of the traced statements for `PDOStatement::execute`.
+    ///
+    /// Notice that the value of `bindParam` is the one when it's bound.
+    fn hook_pdo_statement_bind(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+
+                let handle = get_this_mut(execute_data)?.handle();
+                if !DSN_MAP.with(|dsn_map| dsn_map.borrow().contains_key(&handle)) {
+                    return Ok(Box::new(()));
+                }
+
+                let key = execute_data.get_parameter(0);
+                let key = match (key.as_long(), key.as_z_str()) {
+                    (Some(position), _) => ParameterKey::Position(position),
+                    (_, Some(name)) => ParameterKey::Name(name.to_str()?.to_owned()),
+                    _ => return Ok(Box::new(())),
+                };
+                let name = match &key {
+                    ParameterKey::Position(_) => None,
+                    ParameterKey::Name(name) => Some(&**name),
+                };
+                let value = format_parameter(name, execute_data.get_parameter(1));
+
+                PARAMETERS_MAP.with(|parameters_map| {
+                    let mut parameters_map = parameters_map.borrow_mut();
+                    let parameters = parameters_map.entry(handle).or_default();
+                    parameters.retain(|(k, _)| *k != key);
+                    parameters.push((key, value));
+                });
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+}
+
+/// Add the parameters passed to `PDOStatement::execute($params)`, or bound
+/// before.
+fn add_statement_parameters(handle: u32, execute_data: &ExecuteData, span: &mut Span) {
+    if execute_data.num_args() >= 1 {
+        if let Some(params) = execute_data.get_parameter(0).as_z_arr() {
+            let parameters = params.iter().map(|(key, value)| match key {
+                IterKey::Index(_) => format_parameter(None, value),
+                IterKey::ZStr(name) => format_parameter(name.to_str().ok(), value),
+            });
+            add_parameters_tag(span, parameters);
+            return;
+        }
+    }
+
+    PARAMETERS_MAP.with(|parameters_map| {
+        if let Some(parameters) = parameters_map.borrow_mut().get_mut(&handle) {
+            parameters.sort_by(|(a, _), (b, _)| a.cmp(b));
+            add_parameters_tag(span, parameters.iter().map(|(_, value)| value.clone()));
+        }
+    });
 }
 
 fn hack_dtor(this: &mut ZObj, new_dtor: sys::zend_object_dtor_obj_t) {
@@ -183,6 +290,9 @@ unsafe extern "C" fn dtor(object: *mut sys::zend_object) {
     DSN_MAP.with(|dsn_map| {
         dsn_map.borrow_mut().remove(&handle);
     });
+    PARAMETERS_MAP.with(|parameters_map| {
+        parameters_map.borrow_mut().remove(&handle);
+    });
     DTOR_MAP.with(|dtor_map| {
         if let Some(Some(dtor)) = dtor_map.borrow_mut().remove(&handle) {
             dtor(object);
@@ -191,8 +301,10 @@ unsafe extern "C" fn dtor(object: *mut sys::zend_object) {
 }
 
 fn after_hook(
-    span: Box<dyn Any>, execute_data: &mut ExecuteData, return_value: &mut ZVal,
+    span: Box<dyn Any>, execute_data: Option<&mut ExecuteData>, return_value: &mut ZVal,
 ) -> anyhow::Result<()> {
+    let execute_data = execute_data.context("execute data is empty")?;
+
     if let Some(b) = return_value.as_bool() {
         if !b {
             return after_hook_when_false(
@@ -201,7 +313,8 @@ fn after_hook(
             );
         }
     } else if let Some(obj) = return_value.as_mut_z_obj() {
-        if obj.get_class().get_name() == &"PDOStatement" {
+        // The statement class can be changed by `PDO::ATTR_STATEMENT_CLASS`.
+        if is_class_or_subclass(unsafe { &*obj.get_class().as_ptr() }, "PDOStatement") {
             return after_hook_when_pdo_statement(get_this_mut(execute_data)?, obj);
         }
     }
@@ -222,7 +335,7 @@ fn after_hook_when_false(this: &mut ZObj, span: &mut Span) -> anyhow::Result<()>
     let error = get_error_info_item(info, 2)?.expect_z_str()?.to_str()?;
 
     span.with_span_object_mut(|span| {
-        span.add_log([("SQLSTATE", state), ("Error Code", code), ("Error", error)]);
+        span.add_redacted_log([("SQLSTATE", state), ("Error Code", code), ("Error", error)]);
     });
 
     Ok(())
@@ -248,17 +361,20 @@ fn get_error_info_item(info: &ZArr, i: u64) -> anyhow::Result<&ZVal> {
         .with_context(|| format!("errorInfo[{}] not exists", i))
 }
 
-fn create_exit_span_with_dsn(
-    class_name: &str, function_name: &str, dsn: &Dsn,
+/// Create the Database layer exit span, shared by the database plugins.
+pub(super) fn create_exit_span_with_dsn(
+    operation_name: &str, component_id: i32, dsn: &Dsn,
 ) -> anyhow::Result<Span> {
     RequestContext::try_with_global_ctx(None, |ctx| {
-        let mut span =
-            ctx.create_exit_span(&format!("{}->{}", class_name, function_name), &dsn.peer);
+        let mut span = ctx.create_exit_span(operation_name, &dsn.peer);
         span.with_span_object_mut(|obj| {
             obj.set_span_layer(SpanLayer::Database);
-            obj.component_id = COMPONENT_PHP_PDO_ID;
+            obj.component_id = component_id;
             obj.add_tag("db.type", &dsn.db_type);
-            obj.add_tag("db.data_source", &dsn.data_source);
+            obj.add_redacted_tag("db.data_source", &dsn.data_source);
+            if let Some(instance) = &dsn.instance {
+                obj.add_redacted_tag("db.instance", instance);
+            }
         });
         Ok(span)
     })
@@ -275,10 +391,11 @@ fn with_dsn<T>(handle: u32, f: impl FnOnce(&Dsn) -> anyhow::Result<T>) -> anyhow
 }
 
 #[derive(Debug, Clone)]
-struct Dsn {
-    db_type: String,
-    data_source: String,
-    peer: String,
+pub(super) struct Dsn {
+    pub(super) db_type: String,
+    pub(super) data_source: String,
+    pub(super) peer: String,
+    pub(super) instance: Option<String>,
 }
 
 impl FromStr for Dsn {
@@ -289,6 +406,16 @@ impl FromStr for Dsn {
         let db_type = ss.next().context("unkonwn db type")?.to_owned();
         let data_source = ss.next().context("unkonwn datasource")?.to_owned();
 
+        // The datasource of sqlite is the database file path.
+        if db_type == "sqlite" {
+            return Ok(Dsn {
+                db_type,
+                peer: data_source.clone(),
+                instance: Some(data_source.clone()),
+                data_source,
+            });
+        }
+
         let mut host = "unknown";
         let mut port = match &*db_type {
             "mysql" => "3306",
@@ -297,6 +424,7 @@ impl FromStr for Dsn {
             "pgsql" => "5432",
             _ => "unknown",
         };
+        let mut instance = None;
 
         let ss = data_source.split(";");
         for s in ss {
@@ -312,6 +440,9 @@ impl FromStr for Dsn {
                 "port" => {
                     port = v;
                 }
+                "dbname" => {
+                    instance = Some(v.to_owned());
+                }
                 _ => {}
             }
         }
@@ -322,6 +453,7 @@ impl FromStr for Dsn {
             db_type,
             data_source,
             peer,
+            instance,
         })
     }
 }
@@ -0,0 +1,419 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use super::{
+    pdo::{create_exit_span_with_dsn, Dsn},
+    sql::add_statement_tag,
+    Plugin,
+};
+use crate::{
+    component::COMPONENT_POSTGRESQL_ID,
+    execute::{validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    redact::RedactedLog,
+    util::z_val_to_string,
+};
+use anyhow::Context;
+use phper::{
+    functions::call,
+    values::{ExecuteData, ZVal},
+};
+use skywalking::context::span::Span;
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+use tracing::debug;
+use url::Url;
+
+thread_local! {
+    /// Connections keyed by the resource id (php < 8.1) or the object handle of
+    /// `PgSql\Connection` (php >= 8.1).
+    static CONNECTIONS: RefCell<HashMap<i64, Connection>> = Default::default();
+
+    /// The last opened connection, used when the connection argument is omitted.
+    static LAST_CONNECTION: Cell<Option<i64>> = Cell::new(None);
+}
+
+#[derive(Default, Clone)]
+pub struct PgsqlPlugin;
+
+impl Plugin for PgsqlPlugin {
+    fn name(&self) -> &'static str {
+        "pgsql"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_POSTGRESQL_ID]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        None
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        Some("pg_")
+    }
+
+    fn hook(
+        &self, _class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match function_name {
+            "pg_connect" | "pg_pconnect" => Some(self.hook_pg_connect()),
+            "pg_close" => Some(self.hook_pg_close()),
+            "pg_query" => Some(self.hook_pg_query_functions(function_name, 2)),
+            "pg_query_params" => Some(self.hook_pg_query_functions(function_name, 3)),
+            "pg_prepare" => Some(self.hook_pg_prepare()),
+            "pg_execute" => Some(self.hook_pg_execute()),
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        CONNECTIONS.with(|connections| connections.borrow_mut().clear());
+        LAST_CONNECTION.with(|last| last.set(None));
+    }
+}
+
+impl PgsqlPlugin {
+    fn hook_pg_connect(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let connection_string = z_val_to_string(execute_data.get_parameter(0))
+                    .context("connection string isn't string")?;
+                let dsn = parse_connection_string(&connection_string);
+                debug!(?dsn, "parse pgsql connection string");
+
+                Ok(Box::new(dsn))
+            }),
+            Box::new(|dsn, _, return_value| {
+                let dsn = *dsn.downcast::<Dsn>().unwrap();
+                if let Some(key) = get_connection_key(return_value) {
+                    CONNECTIONS.with(|connections| {
+                        connections.borrow_mut().insert(
+                            key,
+                            Connection {
+                                dsn,
+                                statements: Default::default(),
+                            },
+                        )
+                    });
+                    LAST_CONNECTION.with(|last| last.set(Some(key)));
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    fn hook_pg_close(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let key = if execute_data.num_args() >= 1 {
+                    get_connection_key(execute_data.get_parameter(0))
+                } else {
+                    LAST_CONNECTION.with(|last| last.get())
+                };
+                if let Some(key) = key {
+                    CONNECTIONS.with(|connections| connections.borrow_mut().remove(&key));
+                    LAST_CONNECTION.with(|last| {
+                        if last.get() == Some(key) {
+                            last.set(None);
+                        }
+                    });
+                }
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    /// Hook the functions like `pg_query([$connection,] $query, ...)`, the
+    /// connection argument exists when the argument count reaches
+    /// `full_num_args`.
+    fn hook_pg_query_functions(
+        &self, function_name: &str, full_num_args: usize,
+    ) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        let function_name = function_name.to_owned();
+        (
+            Box::new(move |execute_data| {
+                let (key, offset) = get_connection_key_and_offset(
+                    execute_data.num_args(),
+                    full_num_args,
+                    execute_data.get_parameter(0),
+                )?;
+
+                let query = z_val_to_string(execute_data.get_parameter(offset));
+
+                let mut span = create_exit_span(key, &function_name)?;
+                if let Some(query) = &query {
+                    add_statement_tag(&mut span, "pgsql", query);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook),
+        )
+    }
+
+    /// Hook `pg_prepare([$connection,] $stmtname, $query)`, save the query for
+    /// `pg_execute`.
+    fn hook_pg_prepare(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let (key, offset) = get_connection_key_and_offset(
+                    execute_data.num_args(),
+                    3,
+                    execute_data.get_parameter(0),
+                )?;
+
+                let name = z_val_to_string(execute_data.get_parameter(offset)).unwrap_or_default();
+                let query = z_val_to_string(execute_data.get_parameter(offset + 1))
+                    .context("query isn't string")?;
+
+                CONNECTIONS.with(|connections| {
+                    if let Some(connection) = connections.borrow_mut().get_mut(&key) {
+                        connection.statements.insert(name, query.clone());
+                    }
+                });
+
+                let mut span = create_exit_span(key, "pg_prepare")?;
+                add_statement_tag(&mut span, "pgsql", &query);
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook),
+        )
+    }
+
+    /// Hook `pg_execute([$connection,] $stmtname, $params)`, the statement is
+    /// saved by `pg_prepare`.
+    fn hook_pg_execute(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let (key, offset) = get_connection_key_and_offset(
+                    execute_data.num_args(),
+                    3,
+                    execute_data.get_parameter(0),
+                )?;
+
+                let name = z_val_to_string(execute_data.get_parameter(offset)).unwrap_or_default();
+                let query = CONNECTIONS.with(|connections| {
+                    connections
+                        .borrow()
+                        .get(&key)
+                        .and_then(|connection| connection.statements.get(&name).cloned())
+                });
+
+                let mut span = create_exit_span(key, "pg_execute")?;
+                if let Some(query) = &query {
+                    add_statement_tag(&mut span, "pgsql", query);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook),
+        )
+    }
+}
+
+fn after_hook(
+    span: Box<dyn Any>, _execute_data: Option<&mut ExecuteData>, return_value: &mut ZVal,
+) -> anyhow::Result<()> {
+    let mut span = span.downcast::<Span>().unwrap();
+
+    if matches!(return_value.as_bool(), Some(false)) {
+        let error = call("pg_last_error", &mut []).ok();
+        let error = error.as_ref().and_then(z_val_to_string).unwrap_or_default();
+        span.with_span_object_mut(|span| {
+            span.is_error = true;
+            span.add_redacted_log([("Error", error)]);
+        });
+    }
+
+    Ok(())
+}
+
+fn create_exit_span(key: i64, function_name: &str) -> anyhow::Result<Span> {
+    CONNECTIONS.with(|connections| {
+        connections
+            .borrow()
+            .get(&key)
+            .context("connection not exists")
+            .and_then(|connection| {
+                create_exit_span_with_dsn(function_name, COMPONENT_POSTGRESQL_ID, &connection.dsn)
+            })
+    })
+}
+
+fn get_connection_key(connection: &ZVal) -> Option<i64> {
+    connection
+        .as_z_res()
+        .map(|res| res.handle())
+        .or_else(|| connection.as_z_obj().map(|obj| obj.handle() as i64))
+}
+
+/// Get the connection key and the offset of the rest arguments.
+fn get_connection_key_and_offset(
+    num_args: usize, full_num_args: usize, first_arg: &ZVal,
+) -> anyhow::Result<(i64, usize)> {
+    if num_args >= full_num_args {
+        let key = get_connection_key(first_arg).context("connection isn't valid")?;
+        Ok((key, 1))
+    } else {
+        let key = LAST_CONNECTION
+            .with(|last| last.get())
+            .context("default connection not exists")?;
+        Ok((key, 0))
+    }
+}
+
+struct Connection {
+    dsn: Dsn,
+    /// Prepared statements, name to query.
+    statements: HashMap<String, String>,
+}
+
+/// Parse the connection string of `pg_connect`, like `host=localhost port=5432
+/// dbname=test`, or `postgresql://user@localhost:5432/test`.
+fn parse_connection_string(connection_string: &str) -> Dsn {
+    let connection_string = connection_string.trim();
+
+    if connection_string.starts_with("postgresql://")
+        || connection_string.starts_with("postgres://")
+    {
+        if let Ok(mut url) = Url::parse(connection_string) {
+            let host = url.host_str().unwrap_or("localhost").to_owned();
+            let port = url.port().unwrap_or(5432);
+            let instance = url.path().trim_start_matches('/').to_owned();
+            let _ = url.set_password(None);
+            return Dsn {
+                db_type: "pgsql".to_owned(),
+                data_source: url.to_string(),
+                peer: format!("{}:{}", host, port),
+                instance: if instance.is_empty() {
+                    None
+                } else {
+                    Some(instance)
+                },
+            };
+        }
+    }
+
+    let mut host = "localhost".to_owned();
+    let mut port = "5432".to_owned();
+    let mut instance = None;
+    let mut data_source = Vec::new();
+
+    for (k, v) in parse_conninfo(connection_string) {
+        match &*k {
+            "host" | "hostaddr" => host = v.clone(),
+            "port" => port = v.clone(),
+            "dbname" => instance = Some(v.clone()),
+            // Don't report the password.
+            "password" => continue,
+            _ => {}
+        }
+        if v.is_empty() || v.contains(char::is_whitespace) {
+            data_source.push(format!("{}='{}'", k, v));
+        } else {
+            data_source.push(format!("{}={}", k, v));
+        }
+    }
+
+    Dsn {
+        db_type: "pgsql".to_owned(),
+        data_source: data_source.join(" "),
+        peer: format!("{}:{}", host, port),
+        instance,
+    }
+}
+
+/// Parse the key value pairs of the libpq connection string, the values can be
+/// single quoted (like `password='a b'`), and escaped by backslash.
+fn parse_conninfo(connection_string: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = connection_string.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        if key.is_empty() {
+            break;
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next_if_eq(&'=').is_none() {
+            break;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\'' => break,
+                    '\\' => value.extend(chars.next()),
+                    _ => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    _ => value.push(c),
+                }
+            }
+        }
+
+        pairs.push((key, value));
+    }
+
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parse_conninfo_plain() {
+        assert_eq!(
+            parse_conninfo("host=127.0.0.1  port = 5432 dbname=db"),
+            pairs(&[("host", "127.0.0.1"), ("port", "5432"), ("dbname", "db")])
+        );
+        assert_eq!(parse_conninfo(""), pairs(&[]));
+    }
+
+    #[test]
+    fn test_parse_conninfo_quoted() {
+        assert_eq!(
+            parse_conninfo("host='db.local' password='a b  c' user=''"),
+            pairs(&[("host", "db.local"), ("password", "a b  c"), ("user", "")])
+        );
+    }
+
+    #[test]
+    fn test_parse_conninfo_escaped() {
+        assert_eq!(
+            parse_conninfo(r"password='it\'s \\ x' user=a\ b port=5432"),
+            pairs(&[("password", r"it's \ x"), ("user", "a b"), ("port", "5432")])
+        );
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use super::{create_consumer_span, finish_consumer_context, mark_span_with_exception, Plugin};
+use crate::{
+    component::{COMPONENT_KAFKA_CONSUMER_ID, COMPONENT_KAFKA_PRODUCER_ID},
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    propagation::inject_propagation,
+    redact::RedactedTag,
+    util::{copy_array, z_val_to_string},
+};
+use anyhow::Context;
+use phper::{
+    arrays::{InsertKey, ZArray},
+    objects::ZObj,
+    values::{ExecuteData, ZVal},
+};
+use skywalking::{context::span::Span, skywalking_proto::v3::SpanLayer};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+use tracing::debug;
+
+/// Index of the `$headers` parameter of `RdKafka\ProducerTopic::producev`.
+const PRODUCEV_HEADERS_INDEX: usize = 4;
+
+thread_local! {
+    /// Broker list and group id of `RdKafka\Conf`, `RdKafka\Producer`,
+    /// `RdKafka\ProducerTopic` and `RdKafka\KafkaConsumer` objects.
+    ///
+    /// The object handles are reused by php after objects destroyed, so the
+    /// stale items will be overwritten rather than growing unlimited.
+    static CONF_MAP: RefCell<HashMap<u32, KafkaConf>> = Default::default();
+
+    /// Is the global request context created by consumer or not.
+    static IS_CONSUMER_CONTEXT: Cell<bool> = Cell::new(false);
+
+    /// The local span of the message consumed in a traced request, finished
+    /// by the next consume.
+    static MESSAGE_SPAN: RefCell<Option<Span>> = Default::default();
+}
+
+#[derive(Default, Clone)]
+pub struct RdKafkaPlugin;
+
+impl Plugin for RdKafkaPlugin {
+    fn name(&self) -> &'static str {
+        "rdkafka"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_KAFKA_PRODUCER_ID, COMPONENT_KAFKA_CONSUMER_ID]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &[
+            "RdKafka",
+            "RdKafka\\Conf",
+            "RdKafka\\Producer",
+            "RdKafka\\ProducerTopic",
+            "RdKafka\\KafkaConsumer",
+        ];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("RdKafka\\Conf"), "set") => Some(self.hook_conf_set()),
+            (Some("RdKafka\\Producer" | "RdKafka\\KafkaConsumer"), "__construct") => {
+                Some(self.hook_construct())
+            }
+            (Some("RdKafka"), "addBrokers") => Some(self.hook_add_brokers()),
+            (Some("RdKafka\\Producer"), "newTopic") => Some(self.hook_producer_new_topic()),
+            (Some("RdKafka\\ProducerTopic"), "produce") => Some(self.hook_produce(false)),
+            (Some("RdKafka\\ProducerTopic"), "producev") => Some(self.hook_produce(true)),
+            (Some("RdKafka\\KafkaConsumer"), "consume") => Some(self.hook_consumer_consume()),
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        MESSAGE_SPAN.with(|span| span.borrow_mut().take());
+    }
+}
+
+impl RdKafkaPlugin {
+    fn hook_conf_set(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+
+                let handle = get_this_mut(execute_data)?.handle();
+                let name = z_val_to_string(execute_data.get_parameter(0)).unwrap_or_default();
+                let value = z_val_to_string(execute_data.get_parameter(1)).unwrap_or_default();
+
+                with_conf_mut(handle, |conf| match &*name {
+                    "metadata.broker.list" | "bootstrap.servers" => conf.brokers = Some(value),
+                    "group.id" => conf.group_id = Some(value),
+                    _ => {}
+                });
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    /// Copy the config from `RdKafka\Conf` to `RdKafka\Producer` or
+    /// `RdKafka\KafkaConsumer`.
+    fn hook_construct(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let handle = get_this_mut(execute_data)?.handle();
+
+                let conf = if execute_data.num_args() >= 1 {
+                    execute_data
+                        .get_parameter(0)
+                        .as_z_obj()
+                        .map(|conf| get_conf(conf.handle()))
+                        .unwrap_or_default()
+                } else {
+                    Default::default()
+                };
+
+                CONF_MAP.with(|conf_map| conf_map.borrow_mut().insert(handle, conf));
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    fn hook_add_brokers(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let handle = get_this_mut(execute_data)?.handle();
+                let brokers = z_val_to_string(execute_data.get_parameter(0))
+                    .context("broker list isn't string")?;
+
+                with_conf_mut(handle, |conf| {
+                    conf.brokers = Some(match conf.brokers.take() {
+                        Some(ori_brokers) if !ori_brokers.is_empty() => {
+                            format!("{},{}", ori_brokers, brokers)
+                        }
+                        _ => brokers,
+                    });
+                });
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    fn hook_producer_new_topic(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| Ok(Box::new(get_this_mut(execute_data)?.handle()))),
+            Box::new(|producer_handle, _, return_value| {
+                let producer_handle = *producer_handle.downcast::<u32>().unwrap();
+                if let Some(topic) = return_value.as_z_obj() {
+                    let conf = get_conf(producer_handle);
+                    CONF_MAP.with(|conf_map| conf_map.borrow_mut().insert(topic.handle(), conf));
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    fn hook_produce(&self, is_producev: bool) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(move |execute_data| {
+                let this = get_this_mut(execute_data)?;
+                let topic = this.call("getName", [])?;
+                let topic = z_val_to_string(&topic).context("topic name isn't string")?;
+                let peer = get_conf(this.handle()).peer();
+
+                let operation_name = format!("Kafka/{}/Producer", topic);
+
+                let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+                    Ok(ctx.create_exit_span(&operation_name, &peer))
+                })?;
+
+                span.with_span_object_mut(|span| {
+                    span.set_span_layer(SpanLayer::Mq);
+                    span.component_id = COMPONENT_KAFKA_PRODUCER_ID;
+                    span.add_redacted_tag("mq.broker", &peer);
+                    span.add_redacted_tag("mq.topic", &topic);
+                });
+
+                if is_producev {
+                    inject_headers(execute_data, &operation_name, &peer)?;
+                } else {
+                    debug!("RdKafka\\ProducerTopic::produce doesn't support headers, skip inject");
+                }
+
+                Ok(Box::new(span) as _)
+            }),
+            Box::new(after_produce),
+        )
+    }
+
+    fn hook_consumer_consume(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                // The processing of the previous message is finished when the next
+                // consume is called.
+                finish_message_context()?;
+
+                Ok(Box::new(get_this_mut(execute_data)?.handle()))
+            }),
+            Box::new(|consumer_handle, _, return_value| {
+                let consumer_handle = *consumer_handle.downcast::<u32>().unwrap();
+                let message = match return_value.as_z_obj() {
+                    Some(message) => message,
+                    None => return Ok(()),
+                };
+
+                // Skip the timeout and partition eof, etc.
+                if message.get_property("err").as_long().unwrap_or_default() != 0 {
+                    return Ok(());
+                }
+
+                create_message_span(get_conf(consumer_handle), message)
+            }),
+        )
+    }
+}
+
+/// Add the propagation headers into the copy of the `$headers` argument of
+/// `RdKafka\ProducerTopic::producev`.
+///
+/// The argument can't be added when it isn't passed, so the message is
+/// produced without propagation headers.
+fn inject_headers(
+    execute_data: &mut ExecuteData, operation_name: &str, peer: &str,
+) -> anyhow::Result<()> {
+    if execute_data.num_args() <= PRODUCEV_HEADERS_INDEX {
+        debug!("RdKafka\\ProducerTopic::producev hasn't headers argument, skip inject");
+        return Ok(());
+    }
+
+    let propagation_headers = inject_propagation(None, operation_name, peer)?;
+
+    let headers = execute_data.get_mut_parameter(PRODUCEV_HEADERS_INDEX);
+    let mut new_headers = match headers.as_z_arr() {
+        Some(headers) => copy_array(headers),
+        None if headers.get_type_info().is_null() => ZArray::new(),
+        None => {
+            debug!("RdKafka\\ProducerTopic::producev headers argument isn't array, skip inject");
+            return Ok(());
+        }
+    };
+    for (name, value) in propagation_headers {
+        new_headers.insert(InsertKey::Str(name), ZVal::from(value));
+    }
+    *headers = ZVal::from(new_headers);
+
+    Ok(())
+}
+
+fn after_produce(
+    span: Box<dyn Any>, _execute_data: Option<&mut ExecuteData>, _return_value: &mut ZVal,
+) -> anyhow::Result<()> {
+    let mut span = span.downcast::<Span>().unwrap();
+
+    // Since php-rdkafka 5, produce will throw exception rather than return error.
+    mark_span_with_exception(&mut span)
+}
+
+fn create_message_span(conf: KafkaConf, message: &ZObj) -> anyhow::Result<()> {
+    let topic = z_val_to_string(message.get_property("topic_name")).unwrap_or_default();
+    let peer = conf.peer();
+    let operation_name = format!(
+        "Kafka/{}/Consumer/{}",
+        topic,
+        conf.group_id.as_deref().unwrap_or_default()
+    );
+
+    let headers = message.get_property("headers").as_z_arr();
+    let get_header = |name: &str| headers?.get(name).and_then(z_val_to_string);
+
+    let span = create_consumer_span(&operation_name, get_header, |span| {
+        span.set_span_layer(SpanLayer::Mq);
+        span.component_id = COMPONENT_KAFKA_CONSUMER_ID;
+        span.peer = peer.clone();
+        span.add_redacted_tag("mq.broker", &peer);
+        span.add_redacted_tag("mq.topic", &topic);
+    })?;
+
+    // Consume in a traced request (such as fpm), the local span is kept,
+    // otherwise the context is kept, both are finished by the next consume.
+    match span {
+        Some(span) => MESSAGE_SPAN.with(|message_span| *message_span.borrow_mut() = Some(span)),
+        None => IS_CONSUMER_CONTEXT.with(|is_consumer| is_consumer.set(true)),
+    }
+
+    Ok(())
+}
+
+fn finish_message_context() -> anyhow::Result<()> {
+    MESSAGE_SPAN.with(|span| span.borrow_mut().take());
+    if IS_CONSUMER_CONTEXT.with(|is_consumer| is_consumer.replace(false)) {
+        finish_consumer_context()?;
+    }
+    Ok(())
+}
+
+fn get_conf(handle: u32) -> KafkaConf {
+    CONF_MAP.with(|conf_map| conf_map.borrow().get(&handle).cloned().unwrap_or_default())
+}
+
+fn with_conf_mut<T>(handle: u32, f: impl FnOnce(&mut KafkaConf) -> T) -> T {
+    CONF_MAP.with(|conf_map| f(conf_map.borrow_mut().entry(handle).or_default()))
+}
+
+#[derive(Debug, Default, Clone)]
+struct KafkaConf {
+    brokers: Option<String>,
+    group_id: Option<String>,
+}
+
+impl KafkaConf {
+    fn peer(&self) -> String {
+        match self.brokers.as_deref() {
+            Some(brokers) if !brokers.is_empty() => brokers.replace(' ', ""),
+            _ => "unknown:9092".to_string(),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! The `db.statement` and `db.sql.parameters` tags of the database plugins,
+//! controlled by the ini `skywalking_agent.sql_*`.
+
+use crate::{
+    redact::RedactedTag, SKYWALKING_AGENT_SQL_PARAMETERS_ENABLE,
+    SKYWALKING_AGENT_SQL_PARAMETERS_MASK, SKYWALKING_AGENT_SQL_STATEMENT_MAX_LENGTH,
+    SKYWALKING_AGENT_SQL_STATEMENT_MODE,
+};
+use once_cell::sync::Lazy;
+use phper::{ini::Ini, sys, values::ZVal};
+use skywalking::context::span::Span;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatementMode {
+    Off,
+    Raw,
+    Normalized,
+}
+
+static STATEMENT_MODE: Lazy<StatementMode> = Lazy::new(|| {
+    let mode = Ini::get::<String>(SKYWALKING_AGENT_SQL_STATEMENT_MODE).unwrap_or_default();
+    match &*mode.trim().to_ascii_lowercase() {
+        "off" => StatementMode::Off,
+        "raw" | "" => StatementMode::Raw,
+        "normalized" => StatementMode::Normalized,
+        _ => {
+            warn!(mode, "unknown sql statement mode, use normalized");
+            StatementMode::Normalized
+        }
+    }
+});
+
+static STATEMENT_MAX_LENGTH: Lazy<usize> = Lazy::new(|| {
+    let max_length = Ini::get::<i64>(SKYWALKING_AGENT_SQL_STATEMENT_MAX_LENGTH).unwrap_or(0);
+    if max_length <= 0 {
+        usize::MAX
+    } else {
+        max_length as usize
+    }
+});
+
+pub(super) static PARAMETERS_ENABLE: Lazy<bool> =
+    Lazy::new(|| Ini::get::<bool>(SKYWALKING_AGENT_SQL_PARAMETERS_ENABLE).unwrap_or_default());
+
+/// The lowercase keywords of the parameter names to mask.
+static PARAMETERS_MASK: Lazy<Vec<String>> = Lazy::new(|| {
+    Ini::get::<String>(SKYWALKING_AGENT_SQL_PARAMETERS_MASK)
+        .unwrap_or_default()
+        .split(',')
+        .map(|keyword| keyword.trim().to_ascii_lowercase())
+        .filter(|keyword| !keyword.is_empty())
+        .collect()
+});
+
+const MASKED_VALUE: &str = "***";
+
+/// Add the tag `db.statement` by the statement mode, the double quoted strings
+/// are literals in mysql, and identifiers in the others.
+pub(super) fn add_statement_tag(span: &mut Span, db_type: &str, statement: &str) {
+    let statement = match *STATEMENT_MODE {
+        StatementMode::Off => return,
+        StatementMode::Raw => statement.to_owned(),
+        StatementMode::Normalized => normalize(statement, db_type == "mysql"),
+    };
+    span.add_redacted_tag("db.statement", &truncate(statement));
+}
+
+/// Add the tag `db.sql.parameters`, like `[1,***]` or `[:id=1,:name=foo]`, the
+/// parameters are formatted by [format_parameter].
+pub(super) fn add_parameters_tag(span: &mut Span, parameters: impl IntoIterator<Item = String>) {
+    let parameters = parameters.into_iter().collect::<Vec<_>>();
+    if !parameters.is_empty() {
+        let parameters = truncate(format!("[{}]", parameters.join(",")));
+        span.add_redacted_tag("db.sql.parameters", &parameters);
+    }
+}
+
+/// Format the parameter, the values of the parameters whose name contains the
+/// mask keywords are masked, and the string values of the positional
+/// parameters are masked too (if any mask keyword is configured), because
+/// there is no name to check.
+pub(super) fn format_parameter(name: Option<&str>, value: &ZVal) -> String {
+    let value = deref(value);
+    match name {
+        Some(name) => {
+            if is_masked_name(name) {
+                format!("{name}={MASKED_VALUE}")
+            } else {
+                format!("{name}={}", format_value(value))
+            }
+        }
+        None if !PARAMETERS_MASK.is_empty() && value.as_z_str().is_some() => {
+            MASKED_VALUE.to_owned()
+        }
+        None => format_value(value),
+    }
+}
+
+fn is_masked_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    PARAMETERS_MASK.iter().any(|keyword| name.contains(&**keyword))
+}
+
+fn format_value(value: &ZVal) -> String {
+    let value = deref(value);
+    if let Some(s) = value.as_z_str() {
+        return s.to_str().map(ToOwned::to_owned).unwrap_or_else(|_| "(binary)".to_owned());
+    }
+    if let Some(n) = value.as_long() {
+        return n.to_string();
+    }
+    if let Some(n) = value.as_double() {
+        return n.to_string();
+    }
+    if let Some(b) = value.as_bool() {
+        return b.to_string();
+    }
+    "null".to_owned()
+}
+
+/// The variables bound by `PDOStatement::bindParam` are references.
+fn deref(value: &ZVal) -> &ZVal {
+    unsafe {
+        let ptr = value.as_ptr();
+        if (*ptr).u1.type_info & 0xff == sys::IS_REFERENCE {
+            ZVal::from_ptr(&(*(*ptr).value.ref_).val)
+        } else {
+            value
+        }
+    }
+}
+
+fn truncate(mut s: String) -> String {
+    let max_length = *STATEMENT_MAX_LENGTH;
+    if s.len() > max_length {
+        let mut end = max_length;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+        s.push_str("...");
+    }
+    s
+}
+
+/// Replace the literals (strings and numbers) of sql with `?`, and remove the
+/// comments, the placeholders and identifiers are kept.
+///
+/// The double quoted strings are literals, `#` starts a comment and backslash
+/// escapes in strings only in mysql, the others follow the standard, except
+/// the escape strings of postgresql like `E'\n'`.
+fn normalize(sql: &str, is_mysql: bool) -> String {
+    let chars = sql.chars().collect::<Vec<_>>();
+    let mut normalized = String::with_capacity(sql.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        match c {
+            '\'' => {
+                let backslash_escape = is_mysql || is_escape_string(&chars, i);
+                if backslash_escape && !is_mysql {
+                    // Remove the prefix `E` of the escape string.
+                    normalized.pop();
+                }
+                i = skip_quoted(&chars, i, '\'', backslash_escape);
+                normalized.push('?');
+            }
+            '"' if is_mysql => {
+                i = skip_quoted(&chars, i, '"', true);
+                normalized.push('?');
+            }
+            '"' | '`' => {
+                let end = skip_quoted(&chars, i, c, false);
+                normalized.extend(&chars[i..end]);
+                i = end;
+            }
+            '-' if next == Some('-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '#' if is_mysql => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if next == Some('*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i = (i + 2).min(chars.len());
+            }
+            // Dollar quoted strings of postgresql, like `$$text$$` or `$tag$text$tag$`.
+            '$' if !is_mysql && !next.map_or(false, |c| c.is_ascii_digit()) => {
+                match dollar_tag_end(&chars, i) {
+                    Some(tag_end) => {
+                        let tag = &chars[i..tag_end];
+                        i = tag_end;
+                        while i < chars.len() && !chars[i..].starts_with(tag) {
+                            i += 1;
+                        }
+                        i = (i + tag.len()).min(chars.len());
+                        normalized.push('?');
+                    }
+                    None => {
+                        normalized.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            c if c.is_ascii_digit() && !is_identifier_char(normalized.chars().last()) => {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                    i += 1;
+                }
+                normalized.push('?');
+            }
+            c => {
+                normalized.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    normalized
+}
+
+fn is_identifier_char(c: Option<char>) -> bool {
+    c.map_or(false, |c| c.is_alphanumeric() || matches!(c, '_' | '$' | ':' | '@'))
+}
+
+/// Whether the quote at `start` begins an escape string of postgresql, like
+/// `E'\n'`.
+fn is_escape_string(chars: &[char], start: usize) -> bool {
+    start >= 1
+        && matches!(chars[start - 1], 'E' | 'e')
+        && (start < 2 || !is_identifier_char(Some(chars[start - 2])))
+}
+
+/// Skip the quoted string started at `start`, the quote is escaped by
+/// doubling, or by backslash if `backslash_escape`, return the index after the
+/// end quote.
+fn skip_quoted(chars: &[char], start: usize, quote: char, backslash_escape: bool) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        if backslash_escape && chars[i] == '\\' {
+            i += 2;
+        } else if chars[i] == quote {
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    chars.len()
+}
+
+/// Return the index after the dollar quote tag started at `start`.
+fn dollar_tag_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '$' => return Some(i + 1),
+            c if c.is_alphanumeric() || c == '_' => i += 1,
+            _ => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_normalize_literals() {
+        assert_eq!(
+            normalize("SELECT * FROM t WHERE a = 'x' AND b = 12.5 AND c = ?", false),
+            "SELECT * FROM t WHERE a = ? AND b = ? AND c = ?"
+        );
+        assert_eq!(
+            normalize("SELECT \"a\", `b` FROM t1 WHERE c = \"x\"", true),
+            "SELECT ?, `b` FROM t1 WHERE c = ?"
+        );
+        assert_eq!(
+            normalize("SELECT \"a\" FROM t1 WHERE c = :c1", false),
+            "SELECT \"a\" FROM t1 WHERE c = :c1"
+        );
+    }
+
+    #[test]
+    fn test_normalize_backslash() {
+        // The backslash is a plain char in the standard strings of postgresql.
+        assert_eq!(
+            normalize("SELECT 'C:\\' AS a, 'secret' AS b", false),
+            "SELECT ? AS a, ? AS b"
+        );
+        assert_eq!(normalize("SELECT E'it\\'s', 'x'", false), "SELECT ?, ?");
+        assert_eq!(normalize("SELECT 'it\\'s', 'x'", true), "SELECT ?, ?");
+        assert_eq!(normalize("SELECT 'it''s'", false), "SELECT ?");
+    }
+
+    #[test]
+    fn test_normalize_dollar() {
+        assert_eq!(
+            normalize("SELECT $1, $tag$it's$tag$, $$x$$ FROM t", false),
+            "SELECT $1, ?, ? FROM t"
+        );
+        assert_eq!(normalize("SELECT a$b FROM t", false), "SELECT a$b FROM t");
+    }
+
+    #[test]
+    fn test_normalize_comments() {
+        assert_eq!(
+            normalize("SELECT 1 -- 'x'\nFROM t /* 2 */ WHERE a = 3", false),
+            "SELECT ? \nFROM t  WHERE a = ?"
+        );
+        assert_eq!(normalize("SELECT 1 # x\nFROM t", true), "SELECT ? \nFROM t");
+        assert_eq!(normalize("SELECT 1 /* x", false), "SELECT ? ");
+    }
+
+    #[test]
+    fn test_skip_quoted() {
+        let sql = chars("'a\\'b' c");
+        assert_eq!(skip_quoted(&sql, 0, '\'', false), 4);
+        assert_eq!(skip_quoted(&sql, 0, '\'', true), 6);
+        assert_eq!(skip_quoted(&chars("'a''b' c"), 0, '\'', false), 6);
+        assert_eq!(skip_quoted(&chars("'abc"), 0, '\'', false), 4);
+    }
+
+    #[test]
+    fn test_dollar_tag_end() {
+        assert_eq!(dollar_tag_end(&chars("$$x$$"), 0), Some(2));
+        assert_eq!(dollar_tag_end(&chars("$tag_1$x$tag_1$"), 0), Some(7));
+        assert_eq!(dollar_tag_end(&chars("$1"), 0), None);
+        assert_eq!(dollar_tag_end(&chars("$a b$"), 0), None);
+    }
+}
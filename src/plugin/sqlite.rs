@@ -0,0 +1,186 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use super::{
+    is_class_or_subclass,
+    pdo::{create_exit_span_with_dsn, Dsn},
+    sql::add_statement_tag,
+    Plugin,
+};
+use crate::{
+    component::COMPONENT_SQLITE_ID,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    redact::RedactedLog,
+    util::z_val_to_string,
+};
+use anyhow::Context;
+use phper::values::{ExecuteData, ZVal};
+use skywalking::context::span::Span;
+use std::{any::Any, cell::RefCell, collections::HashMap};
+use tracing::debug;
+
+thread_local! {
+    /// Database of `SQLite3` and `SQLite3Stmt` objects.
+    ///
+    /// The object handles are reused by php after objects destroyed, so the
+    /// stale items will be overwritten rather than growing unlimited.
+    static DSN_MAP: RefCell<HashMap<u32, Dsn>> = Default::default();
+}
+
+#[derive(Default, Clone)]
+pub struct SqlitePlugin;
+
+impl Plugin for SqlitePlugin {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_SQLITE_ID]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["SQLite3", "SQLite3Stmt"];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("SQLite3"), "__construct" | "open") => Some(self.hook_sqlite3_open()),
+            (Some("SQLite3"), "exec" | "query" | "querySingle" | "prepare") => {
+                Some(self.hook_sqlite3_methods(function_name))
+            }
+            (Some("SQLite3Stmt"), "execute") => Some(self.hook_sqlite3_stmt_execute()),
+            _ => None,
+        }
+    }
+}
+
+impl SqlitePlugin {
+    fn hook_sqlite3_open(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let handle = get_this_mut(execute_data)?.handle();
+                let filename = z_val_to_string(execute_data.get_parameter(0))
+                    .context("filename isn't string")?;
+
+                debug!(filename, "open SQLite3");
+
+                DSN_MAP.with(|dsn_map| {
+                    dsn_map.borrow_mut().insert(
+                        handle,
+                        Dsn {
+                            db_type: "sqlite".to_owned(),
+                            data_source: filename.clone(),
+                            peer: filename.clone(),
+                            instance: Some(filename),
+                        },
+                    )
+                });
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    fn hook_sqlite3_methods(
+        &self, function_name: &str,
+    ) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        let function_name = function_name.to_owned();
+        (
+            Box::new(move |execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let handle = get_this_mut(execute_data)?.handle();
+                let mut span = create_exit_span(handle, &format!("SQLite3->{}", function_name))?;
+
+                if let Some(statement) = z_val_to_string(execute_data.get_parameter(0)) {
+                    add_statement_tag(&mut span, "sqlite", &statement);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook),
+        )
+    }
+
+    fn hook_sqlite3_stmt_execute(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let this = get_this_mut(execute_data)?;
+                let handle = this.handle();
+                let mut span = create_exit_span(handle, "SQLite3Stmt->execute")?;
+
+                // `SQLite3Stmt::getSQL` is supported since php 7.4.
+                if let Ok(statement) = this.call("getSQL", []) {
+                    if let Some(statement) = z_val_to_string(&statement) {
+                        add_statement_tag(&mut span, "sqlite", &statement);
+                    }
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(|span, _, return_value| {
+                let mut span = span.downcast::<Span>().unwrap();
+                if matches!(return_value.as_bool(), Some(false)) {
+                    span.with_span_object_mut(|span| span.is_error = true);
+                }
+                Ok(())
+            }),
+        )
+    }
+}
+
+fn after_hook(
+    span: Box<dyn Any>, execute_data: Option<&mut ExecuteData>, return_value: &mut ZVal,
+) -> anyhow::Result<()> {
+    let mut span = span.downcast::<Span>().unwrap();
+    let this = get_this_mut(execute_data.context("execute data is empty")?)?;
+
+    if matches!(return_value.as_bool(), Some(false)) {
+        let code = this.call("lastErrorCode", [])?;
+        let code = code.as_long().unwrap_or_default().to_string();
+        let error = this.call("lastErrorMsg", [])?;
+        let error = z_val_to_string(&error).unwrap_or_default();
+
+        span.with_span_object_mut(|span| {
+            span.is_error = true;
+            span.add_redacted_log([("Error Code", code), ("Error", error)]);
+        });
+    } else if let Some(stmt) = return_value.as_z_obj() {
+        if is_class_or_subclass(unsafe { &*stmt.get_class().as_ptr() }, "SQLite3Stmt") {
+            let dsn = DSN_MAP.with(|dsn_map| dsn_map.borrow().get(&this.handle()).cloned());
+            if let Some(dsn) = dsn {
+                DSN_MAP.with(|dsn_map| dsn_map.borrow_mut().insert(stmt.handle(), dsn));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn create_exit_span(handle: u32, operation_name: &str) -> anyhow::Result<Span> {
+    DSN_MAP.with(|dsn_map| {
+        dsn_map
+            .borrow()
+            .get(&handle)
+            .context("dsn not exists")
+            .and_then(|dsn| create_exit_span_with_dsn(operation_name, COMPONENT_SQLITE_ID, dsn))
+    })
+}
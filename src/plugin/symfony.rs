@@ -0,0 +1,508 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [symfony](https://symfony.com/), includes the HttpKernel, Twig
+//! and Messenger components.
+
+use super::{
+    after_hook_consumer, after_hook_with_exception, create_consumer_span, create_local_span,
+    mark_span_with_caught_exception, mark_span_with_exception, Plugin,
+};
+use crate::{
+    component::COMPONENT_PHP_ID,
+    context::RequestContext,
+    execute::{validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    propagation::inject_propagation,
+    redact::RedactedTag,
+    util::{copy_array, z_val_to_string},
+};
+use anyhow::Context;
+use phper::{
+    arrays::{InsertKey, IterKey, ZArray},
+    objects::ZObj,
+    values::ZVal,
+};
+use skywalking::{context::span::Span, skywalking_proto::v3::SpanLayer};
+use std::{cell::RefCell, collections::HashMap};
+use tracing::debug;
+
+/// The `HttpKernelInterface::MAIN_REQUEST`.
+const MAIN_REQUEST: i64 = 1;
+
+const RECEIVED_STAMP_CLASS: &str = "Symfony\\Component\\Messenger\\Stamp\\ReceivedStamp";
+
+thread_local! {
+    /// The spans of controller invocation with the handle of their requests,
+    /// created after the arguments resolved, and finished when the response
+    /// is filtered or the exception is handled, the sub requests are pushed
+    /// above the main request.
+    static CONTROLLER_SPANS: RefCell<Vec<(u32, Span)>> = Default::default();
+
+    /// The propagation headers of the messages being sent, added into the
+    /// headers of the encoded envelopes by the transport serializers.
+    static SENDING_HEADERS: RefCell<Vec<Vec<(&'static str, String)>>> = Default::default();
+
+    /// The headers of the decoded envelopes, keyed by the handle of message
+    /// objects, which are kept when the envelopes are stamped.
+    static RECEIVED_HEADERS: RefCell<HashMap<u32, Vec<(String, String)>>> = Default::default();
+}
+
+const SERIALIZER_CLASS: &str =
+    "Symfony\\Component\\Messenger\\Transport\\Serialization\\Serializer";
+
+const PHP_SERIALIZER_CLASS: &str =
+    "Symfony\\Component\\Messenger\\Transport\\Serialization\\PhpSerializer";
+
+#[derive(Default, Clone)]
+pub struct SymfonyPlugin;
+
+impl Plugin for SymfonyPlugin {
+    fn name(&self) -> &'static str {
+        "symfony"
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &[
+            "Symfony\\Component\\HttpKernel\\HttpKernel",
+            "Symfony\\Component\\HttpKernel\\Controller\\ArgumentResolver",
+            "Twig\\Environment",
+            "Symfony\\Component\\Messenger\\Middleware\\SendMessageMiddleware",
+            "Symfony\\Component\\Messenger\\Middleware\\HandleMessageMiddleware",
+            SERIALIZER_CLASS,
+            PHP_SERIALIZER_CLASS,
+        ];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("Symfony\\Component\\HttpKernel\\HttpKernel"), "handle") => {
+                Some(self.hook_http_kernel_handle())
+            }
+            (Some("Symfony\\Component\\HttpKernel\\HttpKernel"), "filterResponse") => {
+                Some(self.hook_http_kernel_filter_response())
+            }
+            (
+                Some("Symfony\\Component\\HttpKernel\\HttpKernel"),
+                "handleThrowable" | "handleException",
+            ) => Some(self.hook_http_kernel_handle_throwable()),
+            (
+                Some("Symfony\\Component\\HttpKernel\\Controller\\ArgumentResolver"),
+                "getArguments",
+            ) => Some(self.hook_argument_resolver_get_arguments()),
+            (Some("Twig\\Environment"), "render") => Some(self.hook_twig_render()),
+            (
+                Some("Symfony\\Component\\Messenger\\Middleware\\SendMessageMiddleware"),
+                "handle",
+            ) => Some(self.hook_send_message_middleware()),
+            (
+                Some("Symfony\\Component\\Messenger\\Middleware\\HandleMessageMiddleware"),
+                "handle",
+            ) => Some(self.hook_handle_message_middleware()),
+            (Some(SERIALIZER_CLASS | PHP_SERIALIZER_CLASS), "encode") => {
+                Some(self.hook_serializer_encode())
+            }
+            (Some(SERIALIZER_CLASS | PHP_SERIALIZER_CLASS), "decode") => {
+                Some(self.hook_serializer_decode())
+            }
+            _ => None,
+        }
+    }
+
+    fn clear(&self) {
+        CONTROLLER_SPANS.with(|spans| spans.borrow_mut().clear());
+        SENDING_HEADERS.with(|headers| headers.borrow_mut().clear());
+        RECEIVED_HEADERS.with(|headers| headers.borrow_mut().clear());
+    }
+}
+
+impl SymfonyPlugin {
+    /// Hook `HttpKernel::handle(Request $request, int $type = MAIN_REQUEST,
+    /// bool $catch = true)`, rename the entry span with the matched route.
+    fn hook_http_kernel_handle(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let request = execute_data.get_parameter(0).clone();
+                let request_handle = get_request_handle(&request)?;
+                let request_type = if execute_data.num_args() >= 2 {
+                    execute_data
+                        .get_parameter(1)
+                        .as_long()
+                        .unwrap_or(MAIN_REQUEST)
+                } else {
+                    MAIN_REQUEST
+                };
+
+                let span =
+                    create_local_span("Symfony\\Component\\HttpKernel\\HttpKernel->handle")?;
+
+                // Keep the request to get the route after handled, because the arguments
+                // of user function are released before the after hook.
+                let request = if request_type == MAIN_REQUEST {
+                    Some(request)
+                } else {
+                    None
+                };
+
+                Ok(Box::new((span, request_handle, request)))
+            }),
+            Box::new(|data, _, _| {
+                let (mut span, request_handle, request) =
+                    *data.downcast::<(Span, u32, Option<ZVal>)>().unwrap();
+
+                // Finish the controller span if the response isn't filtered.
+                take_controller_span(request_handle);
+
+                mark_span_with_exception(&mut span)?;
+
+                if let Some(mut request) = request {
+                    if let Some(route) = get_route(&mut request) {
+                        debug!(route, "symfony route matched");
+                        RequestContext::rename_entry_span_with_route(None, &route)?;
+                    }
+                }
+
+                Ok(())
+            }),
+        )
+    }
+
+    /// Hook `HttpKernel::filterResponse(Response $response, Request $request,
+    /// int $type)`, finish the controller span of the request.
+    fn hook_http_kernel_filter_response(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+                take_controller_span(get_request_handle(execute_data.get_parameter(1))?);
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    /// Hook `HttpKernel::handleThrowable(\Throwable $e, Request $request, int
+    /// $type)` (`handleException` before symfony 5), which dispatch the
+    /// `kernel.exception` event.
+    fn hook_http_kernel_handle_throwable(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+
+                let request_handle = get_request_handle(execute_data.get_parameter(1))?;
+                if let Some(mut span) = take_controller_span(request_handle) {
+                    span.with_span_object_mut(|span| span.is_error = true);
+                }
+
+                let e = execute_data
+                    .get_parameter(0)
+                    .as_z_obj()
+                    .context("exception isn't object")?;
+                RequestContext::try_with_global(None, |ctx| {
+                    mark_span_with_caught_exception(&mut ctx.entry_span, e)
+                })?;
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    /// Hook `ArgumentResolver::getArguments(Request $request, callable
+    /// $controller)`, which is called before the controller invoked.
+    fn hook_argument_resolver_get_arguments(
+        &self,
+    ) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+                let request_handle = get_request_handle(execute_data.get_parameter(0))?;
+                let controller = get_controller_name(execute_data.get_parameter(1));
+                Ok(Box::new((request_handle, controller)))
+            }),
+            Box::new(|data, _, _| {
+                let (request_handle, controller) = *data.downcast::<(u32, String)>().unwrap();
+                let span = create_local_span(&controller)?;
+                CONTROLLER_SPANS.with(|spans| spans.borrow_mut().push((request_handle, span)));
+                Ok(())
+            }),
+        )
+    }
+
+    fn hook_twig_render(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let mut span = create_local_span("Twig\\Environment->render")?;
+                if let Some(name) = z_val_to_string(execute_data.get_parameter(0)) {
+                    span.add_redacted_tag("view.name", &name);
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+
+    /// Hook `SendMessageMiddleware::handle(Envelope $envelope, StackInterface
+    /// $stack)`, the propagation headers are added by the serializer when the
+    /// envelope is sent to the transport.
+    fn hook_send_message_middleware(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let mut envelope = execute_data.get_parameter(0).clone();
+                let envelope = envelope.as_mut_z_obj().context("envelope isn't object")?;
+
+                // The received message isn't sent again.
+                if get_last_stamp(envelope, RECEIVED_STAMP_CLASS)?.is_some() {
+                    return Ok(Box::new(None::<Span>));
+                }
+
+                let message_class = get_message_class(envelope)?;
+                let operation_name = format!("Symfony/Messenger/{}/Producer", message_class);
+                let peer = "messenger";
+
+                let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+                    Ok(ctx.create_exit_span(&operation_name, peer))
+                })?;
+                span.with_span_object_mut(|span| {
+                    span.set_span_layer(SpanLayer::Mq);
+                    span.component_id = COMPONENT_PHP_ID;
+                    span.add_redacted_tag("mq.message", &message_class);
+                });
+
+                let headers = inject_propagation(None, &operation_name, peer)?;
+                SENDING_HEADERS.with(|sending| sending.borrow_mut().push(headers));
+
+                Ok(Box::new(Some(span)))
+            }),
+            Box::new(|span, _, _| {
+                if let Some(mut span) = *span.downcast::<Option<Span>>().unwrap() {
+                    SENDING_HEADERS.with(|sending| sending.borrow_mut().pop());
+                    mark_span_with_exception(&mut span)?;
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    /// Hook `SerializerInterface::encode(Envelope $envelope): array` of the
+    /// builtin serializers, add the propagation headers into the `headers` of
+    /// the encoded envelope, which are carried by all transports.
+    fn hook_serializer_encode(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Noop::noop(),
+            Box::new(|_, _, return_value| {
+                let headers = SENDING_HEADERS.with(|sending| sending.borrow().last().cloned());
+                let headers = match headers {
+                    Some(headers) => headers,
+                    None => return Ok(()),
+                };
+
+                let encoded = return_value.as_z_arr().context("encoded envelope isn't array")?;
+                let mut new_headers = encoded
+                    .get("headers")
+                    .and_then(|headers| headers.as_z_arr())
+                    .map(copy_array)
+                    .unwrap_or_else(ZArray::new);
+                for (name, value) in headers {
+                    new_headers.insert(InsertKey::Str(name), ZVal::from(value));
+                }
+                let mut new_encoded = copy_array(encoded);
+                new_encoded.insert(InsertKey::Str("headers"), ZVal::from(new_headers));
+                *return_value = ZVal::from(new_encoded);
+
+                Ok(())
+            }),
+        )
+    }
+
+    /// Hook `SerializerInterface::decode(array $encodedEnvelope): Envelope` of
+    /// the builtin serializers, keep the headers for the consumer span.
+    fn hook_serializer_decode(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let headers = execute_data
+                    .get_parameter(0)
+                    .as_z_arr()
+                    .and_then(|encoded| encoded.get("headers"))
+                    .and_then(|headers| headers.as_z_arr())
+                    .map(|headers| {
+                        headers
+                            .iter()
+                            .filter_map(|(key, value)| match key {
+                                IterKey::ZStr(key) => {
+                                    Some((key.to_str().ok()?.to_owned(), z_val_to_string(value)?))
+                                }
+                                IterKey::Index(_) => None,
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+
+                Ok(Box::new(headers))
+            }),
+            Box::new(|headers, _, return_value| {
+                let headers = *headers.downcast::<Vec<(String, String)>>().unwrap();
+
+                let mut envelope = return_value.clone();
+                let envelope = match envelope.as_mut_z_obj() {
+                    Some(envelope) => envelope,
+                    None => return Ok(()),
+                };
+                let message = envelope.call("getMessage", [])?;
+                let message = message.as_z_obj().context("message isn't object")?;
+                RECEIVED_HEADERS.with(|received| {
+                    received.borrow_mut().insert(message.handle(), headers)
+                });
+
+                Ok(())
+            }),
+        )
+    }
+
+    /// Hook `HandleMessageMiddleware::handle(Envelope $envelope, StackInterface
+    /// $stack)`, continue the trace if the message is received from transport.
+    fn hook_handle_message_middleware(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let mut envelope = execute_data.get_parameter(0).clone();
+                let envelope = envelope.as_mut_z_obj().context("envelope isn't object")?;
+
+                let message_class = get_message_class(envelope)?;
+                let operation_name = format!("Symfony/Messenger/{}/Consumer", message_class);
+
+                let received_stamp = get_last_stamp(envelope, RECEIVED_STAMP_CLASS)?;
+                let mut received_stamp = match received_stamp {
+                    Some(received_stamp) => received_stamp,
+                    // Handle the message synchronously.
+                    None => {
+                        let span = create_local_span(&operation_name)?;
+                        return Ok(Box::new(Some(span)));
+                    }
+                };
+
+                let transport_name = received_stamp
+                    .as_mut_z_obj()
+                    .map(|stamp| stamp.call("getTransportName", []))
+                    .transpose()?;
+                let transport_name = transport_name
+                    .as_ref()
+                    .and_then(z_val_to_string)
+                    .unwrap_or_default();
+
+                let message = envelope.call("getMessage", [])?;
+                let message = message.as_z_obj().context("message isn't object")?;
+                let headers = RECEIVED_HEADERS
+                    .with(|received| received.borrow_mut().remove(&message.handle()))
+                    .unwrap_or_default();
+                let get_header = |name: &str| {
+                    headers
+                        .iter()
+                        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.clone())
+                };
+
+                let span = create_consumer_span(&operation_name, get_header, |span| {
+                    span.set_span_layer(SpanLayer::Mq);
+                    span.component_id = COMPONENT_PHP_ID;
+                    span.peer = transport_name.clone();
+                    span.add_redacted_tag("mq.broker", &transport_name);
+                    span.add_redacted_tag("mq.message", &message_class);
+                })?;
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_consumer),
+        )
+    }
+}
+
+/// Get the `_route` from `$request->attributes`.
+fn get_route(request: &mut ZVal) -> Option<String> {
+    let request = request.as_mut_z_obj()?;
+    let mut attributes = request.get_property("attributes").clone();
+    let attributes = attributes.as_mut_z_obj()?;
+    let route = attributes.call("get", [ZVal::from("_route")]).ok()?;
+    z_val_to_string(&route)
+}
+
+/// Get the name of controller callable, like `Class::method`, `[$object,
+/// 'method']` or invokable object.
+fn get_controller_name(controller: &ZVal) -> String {
+    if let Some(name) = z_val_to_string(controller) {
+        return name;
+    }
+
+    if let Some(arr) = controller.as_z_arr() {
+        let class_name = arr.get(0u64).and_then(|class| match class.as_z_obj() {
+            Some(obj) => obj.get_class().get_name().to_str().ok().map(ToOwned::to_owned),
+            None => z_val_to_string(class),
+        });
+        let method = arr.get(1u64).and_then(z_val_to_string);
+        return format!(
+            "{}->{}",
+            class_name.unwrap_or_default(),
+            method.unwrap_or_default()
+        );
+    }
+
+    if let Some(obj) = controller.as_z_obj() {
+        if let Ok(class_name) = obj.get_class().get_name().to_str() {
+            return format!("{}->__invoke", class_name);
+        }
+    }
+
+    "{unknown}".to_owned()
+}
+
+fn get_message_class(envelope: &mut ZObj) -> anyhow::Result<String> {
+    let message = envelope.call("getMessage", [])?;
+    let message = message.as_z_obj().context("message isn't object")?;
+    Ok(message.get_class().get_name().to_str()?.to_owned())
+}
+
+fn get_last_stamp(envelope: &mut ZObj, stamp_class: &str) -> anyhow::Result<Option<ZVal>> {
+    let stamp = envelope.call("last", [ZVal::from(stamp_class)])?;
+    Ok(if stamp.get_type_info().is_null() {
+        None
+    } else {
+        Some(stamp)
+    })
+}
+
+fn get_request_handle(request: &ZVal) -> anyhow::Result<u32> {
+    Ok(request.as_z_obj().context("request isn't object")?.handle())
+}
+
+/// Take the controller span of the request, if it's the innermost one.
+fn take_controller_span(request_handle: u32) -> Option<Span> {
+    CONTROLLER_SPANS.with(|spans| {
+        let mut spans = spans.borrow_mut();
+        match spans.last() {
+            Some((handle, _)) if *handle == request_handle => spans.pop().map(|(_, span)| span),
+            _ => None,
+        }
+    })
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [ThinkPHP](https://www.thinkphp.cn/) 5.1 and 6.
+
+use super::{after_hook_with_exception, create_local_span, Plugin};
+use crate::{
+    context::RequestContext,
+    execute::{get_this_mut, AfterExecuteHook, BeforeExecuteHook, Noop},
+    util::z_val_to_string,
+};
+use anyhow::Context;
+use phper::objects::ZObj;
+use tracing::debug;
+
+#[derive(Default, Clone)]
+pub struct ThinkPhpPlugin;
+
+impl Plugin for ThinkPhpPlugin {
+    fn name(&self) -> &'static str {
+        "thinkphp"
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["think\\route\\Dispatch", "think\\route\\dispatch\\Controller"];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("think\\route\\Dispatch"), "run") => Some(self.hook_dispatch_run()),
+            (Some("think\\route\\dispatch\\Controller"), "exec") => {
+                Some(self.hook_controller_exec())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl ThinkPhpPlugin {
+    /// Hook `think\route\Dispatch::run()`, which is called after the route
+    /// matched, rename the entry span with the route rule, or the
+    /// controller/action if dispatched by url.
+    fn hook_dispatch_run(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let this = get_this_mut(execute_data)?;
+
+                let rule = this
+                    .get_property("rule")
+                    .as_z_obj()
+                    .map(|rule| rule.get_property("rule"))
+                    .and_then(z_val_to_string)
+                    .filter(|rule| !rule.is_empty());
+
+                let route = match rule {
+                    Some(rule) => rule,
+                    None => {
+                        let (controller, action) = get_controller_action(this)?;
+                        format!("{}/{}", controller, action)
+                    }
+                };
+                let route = format!("/{}", route.trim_start_matches('/'));
+
+                debug!(route, "thinkphp route matched");
+
+                RequestContext::rename_entry_span_with_route(None, &route)?;
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    fn hook_controller_exec(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                let this = get_this_mut(execute_data)?;
+                let (controller, action) = get_controller_action(this)?;
+                let span = create_local_span(&format!("{}->{}", controller, action))?;
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+}
+
+fn get_controller_action(dispatch: &mut ZObj) -> anyhow::Result<(String, String)> {
+    let mut request = dispatch.get_property("request").clone();
+    let request = request.as_mut_z_obj().context("request isn't object")?;
+    let controller = request.call("controller", [])?;
+    let action = request.call("action", [])?;
+    Ok((
+        z_val_to_string(&controller).unwrap_or_default(),
+        z_val_to_string(&action).unwrap_or_default(),
+    ))
+}
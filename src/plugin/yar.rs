@@ -0,0 +1,240 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [yar](https://github.com/laruence/yar).
+
+use super::{mark_span_with_exception, Plugin};
+use crate::{
+    component::COMPONENT_PHP_YAR_ID,
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    propagation::{inject_propagation, PROPAGATION_HEADERS},
+    redact::RedactedTag,
+    util::{copy_array, z_val_to_string},
+};
+use anyhow::Context;
+use phper::{
+    arrays::{InsertKey, ZArr, ZArray},
+    values::ZVal,
+};
+use skywalking::{context::span::Span, skywalking_proto::v3::SpanLayer};
+use std::{cell::RefCell, os::raw::c_long};
+use tracing::debug;
+use url::Url;
+
+/// The option `YAR_OPT_HEADER`, only work for http protocol.
+const YAR_OPT_HEADER: c_long = 1 << 4;
+
+/// Index of the `$options` parameter of `Yar_Concurrent_Client::call`.
+const CONCURRENT_OPTIONS_INDEX: usize = 5;
+
+thread_local! {
+    /// Spans of concurrent calls, finished after `Yar_Concurrent_Client::loop`.
+    static CONCURRENT_SPANS: RefCell<Vec<Span>> = Default::default();
+}
+
+#[derive(Default, Clone)]
+pub struct YarPlugin;
+
+impl Plugin for YarPlugin {
+    fn name(&self) -> &'static str {
+        "yar"
+    }
+
+    fn component_ids(&self) -> &'static [i32] {
+        &[COMPONENT_PHP_YAR_ID]
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["Yar_Client", "Yar_Concurrent_Client", "Yar_Server"];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("Yar_Client"), "__call") => Some(self.hook_client_call()),
+            (Some("Yar_Concurrent_Client"), "call") => Some(self.hook_concurrent_client_call()),
+            (Some("Yar_Concurrent_Client"), "loop") => Some(self.hook_concurrent_client_loop()),
+            (Some("Yar_Server"), "handle") => Some(self.hook_server_handle()),
+            _ => None,
+        }
+    }
+}
+
+impl YarPlugin {
+    fn hook_client_call(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let method = z_val_to_string(execute_data.get_parameter(0))
+                    .context("method isn't string")?;
+
+                let this = get_this_mut(execute_data)?;
+                let uri = z_val_to_string(this.get_property("_uri")).context("uri isn't string")?;
+                let (peer, is_http) = get_peer(&uri)?;
+                let operation_name = format!("Yar_Client->{}", method);
+
+                debug!(uri, method, "call yar client");
+
+                let span = create_exit_span(&operation_name, &peer, &uri)?;
+
+                if is_http {
+                    let propagation_headers = inject_propagation(None, &operation_name, &peer)?;
+
+                    let options = this.get_property("_options");
+                    let headers = options
+                        .as_z_arr()
+                        .and_then(|options| options.get(YAR_OPT_HEADER as u64))
+                        .and_then(|headers| headers.as_z_arr());
+                    let headers = new_headers(headers, propagation_headers);
+
+                    this.call("setOpt", [ZVal::from(YAR_OPT_HEADER), headers])
+                        .context("Call Yar_Client::setOpt failed")?;
+                }
+
+                Ok(Box::new(span))
+            }),
+            Box::new(|span, _, _| {
+                let mut span = span.downcast::<Span>().unwrap();
+                mark_span_with_exception(&mut span)
+            }),
+        )
+    }
+
+    /// Hook `Yar_Concurrent_Client::call($uri, $method, $parameters,
+    /// $callback, $error_callback, $options)`.
+    fn hook_concurrent_client_call(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 2)?;
+
+                let uri = z_val_to_string(execute_data.get_parameter(0))
+                    .context("uri isn't string")?;
+                let method = z_val_to_string(execute_data.get_parameter(1))
+                    .context("method isn't string")?;
+                let (peer, is_http) = get_peer(&uri)?;
+                let operation_name = format!("Yar_Concurrent_Client->{}", method);
+
+                debug!(uri, method, "call yar concurrent client");
+
+                let span = create_exit_span(&operation_name, &peer, &uri)?;
+
+                if is_http && execute_data.num_args() > CONCURRENT_OPTIONS_INDEX {
+                    let propagation_headers = inject_propagation(None, &operation_name, &peer)?;
+
+                    let options = execute_data.get_mut_parameter(CONCURRENT_OPTIONS_INDEX);
+                    let mut new_options =
+                        options.as_z_arr().map(copy_array).unwrap_or_else(ZArray::new);
+                    let headers = options
+                        .as_z_arr()
+                        .and_then(|options| options.get(YAR_OPT_HEADER as u64))
+                        .and_then(|headers| headers.as_z_arr());
+                    new_options.insert(
+                        InsertKey::Key(YAR_OPT_HEADER as u64),
+                        new_headers(headers, propagation_headers),
+                    );
+                    *options = ZVal::from(new_options);
+                } else {
+                    debug!("yar concurrent client options is missing, skip inject");
+                }
+
+                CONCURRENT_SPANS.with(|spans| spans.borrow_mut().push(span));
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    fn hook_concurrent_client_loop(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Noop::noop(),
+            Box::new(|_, _, _| {
+                let spans = CONCURRENT_SPANS.with(|spans| spans.take());
+                drop(spans);
+                Ok(())
+            }),
+        )
+    }
+
+    /// The trace is continued by the entry span created in request init, with
+    /// the `sw8` header sent by yar client, so just mark the entry span as yar.
+    fn hook_server_handle(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|_| {
+                RequestContext::with_global(None, |ctx| {
+                    ctx.entry_span.with_span_object_mut(|span| {
+                        span.set_span_layer(SpanLayer::RpcFramework);
+                        span.component_id = COMPONENT_PHP_YAR_ID;
+                    });
+                });
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+}
+
+fn create_exit_span(operation_name: &str, peer: &str, uri: &str) -> anyhow::Result<Span> {
+    let mut span = RequestContext::try_with_global_ctx(None, |ctx| {
+        Ok(ctx.create_exit_span(operation_name, peer))
+    })?;
+
+    span.with_span_object_mut(|span| {
+        span.set_span_layer(SpanLayer::RpcFramework);
+        span.component_id = COMPONENT_PHP_YAR_ID;
+        span.add_redacted_tag("url", uri);
+    });
+
+    Ok(span)
+}
+
+/// Get the peer of uri, and is http protocol or not.
+fn get_peer(uri: &str) -> anyhow::Result<(String, bool)> {
+    let url: Url = uri.parse()?;
+    let is_http = url.scheme() == "http" || url.scheme() == "https";
+    let host = url.host_str().unwrap_or("unknown");
+    let port = url.port_or_known_default().unwrap_or(0);
+    Ok((format!("{}:{}", host, port), is_http))
+}
+
+/// Create the headers with the propagation headers, the old ones are removed,
+/// because the `YAR_OPT_HEADER` of client is reused by the later calls.
+fn new_headers(headers: Option<&ZArr>, propagation_headers: Vec<(&'static str, String)>) -> ZVal {
+    let mut new_headers = ZArray::new();
+    if let Some(headers) = headers {
+        for (_, header) in headers.iter() {
+            if let Some(h) = z_val_to_string(header) {
+                let name = h.split(':').next().unwrap_or_default().trim();
+                if PROPAGATION_HEADERS
+                    .iter()
+                    .any(|propagation_header| name.eq_ignore_ascii_case(propagation_header))
+                {
+                    continue;
+                }
+            }
+            new_headers.insert(InsertKey::NextIndex, header.clone());
+        }
+    }
+    for (name, value) in propagation_headers {
+        new_headers.insert(
+            InsertKey::NextIndex,
+            ZVal::from(format!("{}: {}", name, value)),
+        );
+    }
+    ZVal::from(new_headers)
+}
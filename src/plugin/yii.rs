@@ -0,0 +1,98 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Plugin for [Yii 2](https://www.yiiframework.com/).
+
+use super::{after_hook_with_exception, create_local_span, Plugin};
+use crate::{
+    context::RequestContext,
+    execute::{get_this_mut, validate_num_args, AfterExecuteHook, BeforeExecuteHook, Noop},
+    util::z_val_to_string,
+};
+use anyhow::Context;
+use tracing::debug;
+
+#[derive(Default, Clone)]
+pub struct YiiPlugin;
+
+impl Plugin for YiiPlugin {
+    fn name(&self) -> &'static str {
+        "yii"
+    }
+
+    fn class_names(&self) -> Option<&'static [&'static str]> {
+        static NAMES: &[&str] = &["yii\\base\\Module", "yii\\base\\Controller"];
+        Some(NAMES)
+    }
+
+    fn function_name_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn hook(
+        &self, class_name: Option<&str>, function_name: &str,
+    ) -> Option<(Box<BeforeExecuteHook>, Box<AfterExecuteHook>)> {
+        match (class_name, function_name) {
+            (Some("yii\\base\\Module"), "runAction") => Some(self.hook_module_run_action()),
+            (Some("yii\\base\\Controller"), "runAction") => Some(self.hook_controller_run_action()),
+            _ => None,
+        }
+    }
+}
+
+impl YiiPlugin {
+    /// Hook `yii\base\Module::runAction($route, $params = [])`, which is
+    /// called by `yii\web\Application::handleRequest` with the resolved
+    /// route.
+    ///
+    /// Only the call with the `requestedRoute` of application renames the
+    /// entry span, the nested modules and the error action are skipped.
+    fn hook_module_run_action(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let route = z_val_to_string(execute_data.get_parameter(0))
+                    .context("route isn't string")?;
+                let this = get_this_mut(execute_data)?;
+                let requested_route = z_val_to_string(this.get_property("requestedRoute"));
+
+                if requested_route.as_deref() == Some(route.as_str()) {
+                    let route = format!("/{}", route.trim_start_matches('/'));
+
+                    debug!(route, "yii route resolved");
+
+                    RequestContext::rename_entry_span_with_route(None, &route)?;
+                }
+
+                Ok(Box::new(()))
+            }),
+            Noop::noop(),
+        )
+    }
+
+    /// Hook `yii\base\Controller::runAction($id, $params = [])`.
+    fn hook_controller_run_action(&self) -> (Box<BeforeExecuteHook>, Box<AfterExecuteHook>) {
+        (
+            Box::new(|execute_data| {
+                validate_num_args(execute_data, 1)?;
+
+                let id = z_val_to_string(execute_data.get_parameter(0)).unwrap_or_default();
+                let this = get_this_mut(execute_data)?;
+                let controller = this.get_class().get_name().to_str()?.to_owned();
+
+                let span = create_local_span(&format!("{}->{}", controller, id))?;
+
+                Ok(Box::new(span))
+            }),
+            Box::new(after_hook_with_exception),
+        )
+    }
+}
@@ -0,0 +1,687 @@
+// Copyright (c) 2022 jmjoy
+// Helper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2. You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Propagation of the cross process headers, `sw8`, `sw8-correlation` and
+//! `sw8-x`, and the interop propagators W3C Trace Context and Zipkin B3,
+//! configured by `skywalking_agent.propagators`.
+//!
+//! The interop propagators only keep the trace id, because the parent span of
+//! other vendors can't be referenced by skywalking.
+//!
+//! See: <https://skywalking.apache.org/docs/main/latest/en/protocols/skywalking-cross-process-correlation-headers-protocol-v1/>
+//! and <https://skywalking.apache.org/docs/main/latest/en/protocols/skywalking-cross-process-propagation-headers-protocol-v3/>.
+
+use crate::{
+    context::RequestContext, module::current_tracer,
+    SKYWALKING_AGENT_CORRELATION_ELEMENT_MAX_NUMBER, SKYWALKING_AGENT_CORRELATION_VALUE_MAX_LENGTH,
+    SKYWALKING_AGENT_PROPAGATORS,
+};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use phper::ini::Ini;
+use skywalking::{
+    context::{
+        propagation::{
+            context::PropagationContext, decoder::decode_propagation, encoder::encode_propagation,
+        },
+        span::Span,
+        trace_context::TracingContext,
+    },
+    skywalking_proto::v3::{RefType, SegmentReference},
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+use tracing::{error, trace, warn};
+
+pub const SW8_HEADER: &str = "sw8";
+
+pub const SW8_CORRELATION_HEADER: &str = "sw8-correlation";
+
+pub const SW8_X_HEADER: &str = "sw8-x";
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+pub const B3_HEADER: &str = "b3";
+
+pub const X_B3_TRACE_ID_HEADER: &str = "x-b3-traceid";
+
+pub const X_B3_SPAN_ID_HEADER: &str = "x-b3-spanid";
+
+pub const X_B3_SAMPLED_HEADER: &str = "x-b3-sampled";
+
+/// All headers injected by [inject_propagation], the old values should be
+/// removed before inject, if the carrier is reused.
+pub const PROPAGATION_HEADERS: &[&str] = &[
+    SW8_HEADER,
+    SW8_CORRELATION_HEADER,
+    SW8_X_HEADER,
+    TRACEPARENT_HEADER,
+    TRACESTATE_HEADER,
+    B3_HEADER,
+    X_B3_TRACE_ID_HEADER,
+    X_B3_SPAN_ID_HEADER,
+    X_B3_SAMPLED_HEADER,
+];
+
+/// The propagators to extract in order, and to inject.
+static PROPAGATORS: Lazy<Vec<Propagator>> = Lazy::new(|| {
+    let propagators = Ini::get::<String>(SKYWALKING_AGENT_PROPAGATORS).unwrap_or_default();
+    let mut propagators = Vec::new();
+    for propagator in propagators.split(',').map(str::trim) {
+        if propagator.is_empty() {
+            continue;
+        }
+        match propagator.parse() {
+            Ok(propagator) => {
+                if !propagators.contains(&propagator) {
+                    propagators.push(propagator);
+                }
+            }
+            Err(_) => warn!(propagator, "unknown propagator"),
+        }
+    }
+    if propagators.is_empty() {
+        propagators.push(Propagator::Sw8);
+    }
+    propagators
+});
+
+static CORRELATION_ELEMENT_MAX_NUMBER: Lazy<usize> = Lazy::new(|| {
+    Ini::get::<i64>(SKYWALKING_AGENT_CORRELATION_ELEMENT_MAX_NUMBER).unwrap_or(3) as usize
+});
+
+static CORRELATION_VALUE_MAX_LENGTH: Lazy<usize> = Lazy::new(|| {
+    Ini::get::<i64>(SKYWALKING_AGENT_CORRELATION_VALUE_MAX_LENGTH).unwrap_or(128) as usize
+});
+
+/// The business correlation values, carried by `sw8-correlation`, like
+/// `base64(key):base64(value),base64(key):base64(value)`.
+#[derive(Debug, Default, Clone)]
+pub struct CorrelationContext {
+    data: Vec<(String, String)>,
+}
+
+impl CorrelationContext {
+    pub fn decode(header: &str) -> Self {
+        Self::decode_with_limits(
+            header,
+            *CORRELATION_ELEMENT_MAX_NUMBER,
+            *CORRELATION_VALUE_MAX_LENGTH,
+        )
+    }
+
+    fn decode_with_limits(header: &str, max_number: usize, max_length: usize) -> Self {
+        let mut ctx = Self::default();
+        for element in header.split(',').filter(|element| !element.is_empty()) {
+            let decoded = element.split_once(':').and_then(|(key, value)| {
+                let key = String::from_utf8(base64::decode(key).ok()?).ok()?;
+                let value = String::from_utf8(base64::decode(value).ok()?).ok()?;
+                Some((key, value))
+            });
+            match decoded {
+                Some((key, value)) => {
+                    ctx.put_with_limits(key, value, max_number, max_length);
+                }
+                None => warn!(element, "invalid correlation element"),
+            }
+        }
+        ctx
+    }
+
+    pub fn encode(&self) -> Option<String> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some(
+            self.data
+                .iter()
+                .map(|(key, value)| format!("{}:{}", base64::encode(key), base64::encode(value)))
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Put the key value, return false if exceeds the limits configured by
+    /// ini.
+    pub fn put(&mut self, key: String, value: String) -> bool {
+        self.put_with_limits(
+            key,
+            value,
+            *CORRELATION_ELEMENT_MAX_NUMBER,
+            *CORRELATION_VALUE_MAX_LENGTH,
+        )
+    }
+
+    fn put_with_limits(
+        &mut self, key: String, value: String, max_number: usize, max_length: usize,
+    ) -> bool {
+        if key.is_empty() || value.len() > max_length {
+            return false;
+        }
+        if let Some((_, v)) = self.data.iter_mut().find(|(k, _)| *k == key) {
+            *v = value;
+            return true;
+        }
+        if self.data.len() >= max_number {
+            return false;
+        }
+        self.data.push((key, value));
+        true
+    }
+}
+
+/// The extension fields carried by `sw8-x`, like `{tracing mode}-{timestamp}`.
+#[derive(Debug, Default, Clone)]
+pub struct ExtensionContext {
+    /// Tracing mode `1`, the trace shouldn't be analyzed by the backend.
+    pub skip_analysis: bool,
+}
+
+impl ExtensionContext {
+    pub fn decode(header: &str) -> Self {
+        Self {
+            skip_analysis: header.split('-').next() == Some("1"),
+        }
+    }
+
+    /// The timestamp of sending is always the current time.
+    pub fn encode(&self) -> String {
+        format!(
+            "{}-{}",
+            if self.skip_analysis { 1 } else { 0 },
+            Utc::now().timestamp_millis()
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Propagator {
+    /// `sw8`, `sw8-correlation` and `sw8-x`.
+    Sw8,
+    /// W3C `traceparent` and `tracestate`.
+    TraceContext,
+    /// Zipkin `b3` single header, or `X-B3-*` multi headers, the multi
+    /// headers are injected.
+    B3,
+}
+
+impl FromStr for Propagator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "sw8" => Ok(Self::Sw8),
+            "tracecontext" => Ok(Self::TraceContext),
+            "b3" => Ok(Self::B3),
+            _ => anyhow::bail!("unknown propagator {}", s),
+        }
+    }
+}
+
+/// The contexts extracted from the carrier by [extract_propagation].
+pub struct ExtractedContext {
+    pub tracing_context: TracingContext,
+    pub correlation: CorrelationContext,
+    pub extension: ExtensionContext,
+    pub trace_state: Option<String>,
+    /// The sampled flag of `traceparent` or `b3`, passed through by the
+    /// interop propagators.
+    pub sampled: bool,
+    /// Extracted by the interop propagators, only the trace id is kept.
+    is_foreign: bool,
+}
+
+impl ExtractedContext {
+    /// Create the entry span of the tracing context, the segment continued from
+    /// other vendors has no reference, because the parent span can't be
+    /// referenced by skywalking.
+    pub fn create_entry_span(&mut self, operation_name: &str) -> Span {
+        let mut span = self.tracing_context.create_entry_span(operation_name);
+        if self.is_foreign {
+            span.with_span_object_mut(|span| span.refs.clear());
+        }
+        span
+    }
+}
+
+/// Create the tracing context continued from the carrier, such as the http
+/// headers or the message headers, `get_header` gets the value by the lowercase
+/// header name.
+///
+/// The propagators are tried in the configured order, the first extracted one
+/// is used.
+pub fn extract_propagation(get_header: impl Fn(&str) -> Option<String>) -> ExtractedContext {
+    let mut propagation = None;
+    let mut foreign = None;
+    let mut trace_state = None;
+    let mut correlation = CorrelationContext::default();
+    let mut extension = ExtensionContext::default();
+
+    for propagator in &*PROPAGATORS {
+        match propagator {
+            Propagator::Sw8 => {
+                if propagation.is_none() {
+                    propagation = get_header(SW8_HEADER).and_then(|header| {
+                        match decode_propagation(&header) {
+                            Ok(propagation) => Some(propagation),
+                            Err(e) => {
+                                error!("Decode propagation failed: {}", e);
+                                None
+                            }
+                        }
+                    });
+                }
+                if let Some(header) = get_header(SW8_CORRELATION_HEADER) {
+                    correlation = CorrelationContext::decode(&header);
+                }
+                if let Some(header) = get_header(SW8_X_HEADER) {
+                    extension = ExtensionContext::decode(&header);
+                }
+            }
+            Propagator::TraceContext => {
+                if propagation.is_none() && foreign.is_none() {
+                    foreign = get_header(TRACEPARENT_HEADER)
+                        .and_then(|header| decode_traceparent(&header));
+                }
+                trace_state = get_header(TRACESTATE_HEADER);
+            }
+            Propagator::B3 => {
+                if propagation.is_none() && foreign.is_none() {
+                    foreign = decode_b3(&get_header);
+                }
+            }
+        }
+    }
+
+    trace!("Propagation: {:?}, foreign: {:?}", &propagation, &foreign);
+
+    let is_foreign = propagation.is_none() && foreign.is_some();
+    let mut sampled = true;
+    if let Some((trace_id, foreign_sampled)) = foreign {
+        propagation = Some(foreign_propagation(trace_id));
+        sampled = foreign_sampled;
+    }
+
+    let tracer = current_tracer();
+    let tracing_context = match propagation {
+        Some(propagation) => tracer.create_trace_context_from_propagation(propagation),
+        None => tracer.create_trace_context(),
+    };
+
+    ExtractedContext {
+        tracing_context,
+        correlation,
+        extension,
+        trace_state,
+        sampled,
+        is_foreign,
+    }
+}
+
+/// Whether the carrier has the trace to continue, by the headers of the
+/// configured propagators.
+pub fn is_propagated(get_header: impl Fn(&str) -> Option<String>) -> bool {
+    PROPAGATORS.iter().any(|propagator| match propagator {
+        Propagator::Sw8 => get_header(SW8_HEADER).is_some(),
+        Propagator::TraceContext => get_header(TRACEPARENT_HEADER).is_some(),
+        Propagator::B3 => {
+            get_header(B3_HEADER).is_some() || get_header(X_B3_TRACE_ID_HEADER).is_some()
+        }
+    })
+}
+
+/// Decode `sw8` to the reference of the upstream segment, for the local span of
+/// the message consumed in a traced request, which can't continue the trace of
+/// the message.
+pub fn extract_segment_ref(
+    get_header: impl Fn(&str) -> Option<String>,
+) -> Option<SegmentReference> {
+    if !PROPAGATORS.contains(&Propagator::Sw8) {
+        return None;
+    }
+    let propagation = match decode_propagation(&get_header(SW8_HEADER)?) {
+        Ok(propagation) => propagation,
+        Err(e) => {
+            error!("Decode propagation failed: {}", e);
+            return None;
+        }
+    };
+    Some(SegmentReference {
+        ref_type: RefType::CrossProcess as i32,
+        trace_id: propagation.parent_trace_id,
+        parent_trace_segment_id: propagation.parent_trace_segment_id,
+        parent_span_id: propagation.parent_span_id,
+        parent_service: propagation.parent_service,
+        parent_service_instance: propagation.parent_service_instance,
+        parent_endpoint: propagation.destination_endpoint,
+        network_address_used_at_peer: propagation.destination_address,
+    })
+}
+
+/// Encode the headers to inject into the carrier of exit span, by the global
+/// request context and the configured propagators.
+pub fn inject_propagation(
+    request_id: Option<u64>, endpoint: &str, peer: &str,
+) -> anyhow::Result<Vec<(&'static str, String)>> {
+    RequestContext::try_with_global(request_id, |ctx| {
+        let mut headers = Vec::new();
+        for propagator in &*PROPAGATORS {
+            match propagator {
+                Propagator::Sw8 => {
+                    headers.push((
+                        SW8_HEADER,
+                        encode_propagation(&ctx.tracing_context, endpoint, peer),
+                    ));
+                    if let Some(correlation) = ctx.correlation.encode() {
+                        headers.push((SW8_CORRELATION_HEADER, correlation));
+                    }
+                    headers.push((SW8_X_HEADER, ctx.extension.encode()));
+                }
+                Propagator::TraceContext => {
+                    let trace_id = hex_trace_id(ctx.tracing_context.trace_id());
+                    let span_id = new_hex_span_id(&ctx.tracing_context);
+                    let flags = if ctx.sampled { "01" } else { "00" };
+                    headers.push((
+                        TRACEPARENT_HEADER,
+                        format!("00-{}-{}-{}", trace_id, span_id, flags),
+                    ));
+                    if let Some(trace_state) = &ctx.trace_state {
+                        headers.push((TRACESTATE_HEADER, trace_state.clone()));
+                    }
+                }
+                Propagator::B3 => {
+                    let trace_id = ctx.tracing_context.trace_id();
+                    let trace_id = if is_hex_id(trace_id, 16) {
+                        trace_id.to_owned()
+                    } else {
+                        hex_trace_id(trace_id)
+                    };
+                    headers.push((X_B3_TRACE_ID_HEADER, trace_id));
+                    headers.push((X_B3_SPAN_ID_HEADER, new_hex_span_id(&ctx.tracing_context)));
+                    headers.push((
+                        X_B3_SAMPLED_HEADER,
+                        if ctx.sampled { "1" } else { "0" }.to_owned(),
+                    ));
+                }
+            }
+        }
+        Ok(headers)
+    })
+}
+
+/// Decode `traceparent`, like `00-{trace-id}-{parent-id}-{trace-flags}`, to
+/// the trace id and the sampled flag.
+fn decode_traceparent(header: &str) -> Option<(String, bool)> {
+    let mut fields = header.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+
+    if version == "ff"
+        || !is_hex_id(trace_id, 32)
+        || !is_hex_id(parent_id, 16)
+        || flags.len() != 2
+        || !flags.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        warn!(header, "invalid traceparent");
+        return None;
+    }
+
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_owned(), flags & 1 == 1))
+}
+
+/// Decode the `b3` single header like `{trace-id}-{span-id}-{sampled}`, or the
+/// `X-B3-*` multi headers, to the trace id and the sampled flag.
+fn decode_b3(get_header: &impl Fn(&str) -> Option<String>) -> Option<(String, bool)> {
+    let (trace_id, span_id, sampled) = match get_header(B3_HEADER) {
+        Some(header) => {
+            // The sampling only header, like `0`, `1` or `d`, hasn't the ids.
+            let mut fields = header.trim().split('-');
+            let trace_id = fields.next()?.to_ascii_lowercase();
+            let span_id = fields.next()?.to_ascii_lowercase();
+            let sampled = fields.next().map(ToOwned::to_owned);
+            (trace_id, span_id, sampled)
+        }
+        None => (
+            get_header(X_B3_TRACE_ID_HEADER)?.trim().to_ascii_lowercase(),
+            get_header(X_B3_SPAN_ID_HEADER)?.trim().to_ascii_lowercase(),
+            get_header(X_B3_SAMPLED_HEADER),
+        ),
+    };
+    // The sampling decision is deferred if absent, which is sampled here.
+    let sampled = !matches!(sampled.as_deref().map(str::trim), Some("0" | "false"));
+
+    if !(is_hex_id(&trace_id, 16) || is_hex_id(&trace_id, 32)) || !is_hex_id(&span_id, 16) {
+        warn!(trace_id, span_id, "invalid b3 headers");
+        return None;
+    }
+
+    Some((trace_id, sampled))
+}
+
+/// Create the propagation from other vendors, only the trace id is used, the
+/// reference of the entry span is removed by
+/// [ExtractedContext::create_entry_span].
+fn foreign_propagation(trace_id: String) -> PropagationContext {
+    PropagationContext {
+        do_sample: true,
+        parent_trace_id: trace_id,
+        parent_trace_segment_id: String::new(),
+        parent_span_id: 0,
+        parent_service: String::new(),
+        parent_service_instance: String::new(),
+        destination_endpoint: String::new(),
+        destination_address: String::new(),
+    }
+}
+
+fn is_hex_id(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        && id.bytes().any(|b| b != b'0')
+}
+
+/// Convert the skywalking trace id to 32 lowercase hex, the trace id continued
+/// from `traceparent` is kept.
+fn hex_trace_id(trace_id: &str) -> String {
+    let hex = trace_id
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect::<String>();
+    if hex.len() >= 32 {
+        hex[hex.len() - 32..].to_owned()
+    } else {
+        format!("{:0>32}", hex)
+    }
+}
+
+/// Generate the 16 lowercase hex span id for the interop propagators, which
+/// can't be the skywalking span id.
+fn new_hex_span_id(ctx: &TracingContext) -> String {
+    let mut hasher = DefaultHasher::new();
+    ctx.trace_segment_id().hash(&mut hasher);
+    Utc::now().timestamp_nanos().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(items: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let items = items
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        move |name| {
+            items
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        }
+    }
+
+    #[test]
+    fn test_decode_traceparent() {
+        assert_eq!(
+            decode_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            Some(("0af7651916cd43dd8448eb211c80319c".to_owned(), true))
+        );
+        assert_eq!(
+            decode_traceparent(" 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00 "),
+            Some(("0af7651916cd43dd8448eb211c80319c".to_owned(), false))
+        );
+        assert_eq!(
+            decode_traceparent("ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            None
+        );
+        assert_eq!(
+            decode_traceparent("00-00000000000000000000000000000000-b7ad6b7169203331-01"),
+            None
+        );
+        assert_eq!(
+            decode_traceparent("00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01"),
+            None
+        );
+        assert_eq!(
+            decode_traceparent("00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01"),
+            None
+        );
+        assert_eq!(
+            decode_traceparent("00-0af7651916cd43dd8448eb211c80319c"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_decode_b3_single() {
+        assert_eq!(
+            decode_b3(&headers(&[(
+                B3_HEADER,
+                "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1"
+            )])),
+            Some(("80f198ee56343ba864fe8b2a57d3eff7".to_owned(), true))
+        );
+        assert_eq!(
+            decode_b3(&headers(&[(
+                B3_HEADER,
+                "64FE8B2A57D3EFF7-e457b5a2e4d86bd1-0"
+            )])),
+            Some(("64fe8b2a57d3eff7".to_owned(), false))
+        );
+        assert_eq!(
+            decode_b3(&headers(&[(
+                B3_HEADER,
+                "64fe8b2a57d3eff7-e457b5a2e4d86bd1"
+            )])),
+            Some(("64fe8b2a57d3eff7".to_owned(), true))
+        );
+    }
+
+    #[test]
+    fn test_decode_b3_sampling_only() {
+        assert_eq!(decode_b3(&headers(&[(B3_HEADER, "0")])), None);
+        assert_eq!(decode_b3(&headers(&[(B3_HEADER, "d")])), None);
+    }
+
+    #[test]
+    fn test_decode_b3_multi() {
+        assert_eq!(
+            decode_b3(&headers(&[
+                (X_B3_TRACE_ID_HEADER, "463ac35c9f6413ad48485a3953bb6124"),
+                (X_B3_SPAN_ID_HEADER, "a2fb4a1d1a96d312"),
+                (X_B3_SAMPLED_HEADER, "false"),
+            ])),
+            Some(("463ac35c9f6413ad48485a3953bb6124".to_owned(), false))
+        );
+        assert_eq!(
+            decode_b3(&headers(&[(
+                X_B3_TRACE_ID_HEADER,
+                "463ac35c9f6413ad48485a3953bb6124"
+            )])),
+            None
+        );
+        assert_eq!(
+            decode_b3(&headers(&[
+                (X_B3_TRACE_ID_HEADER, "463ac35c9f6413ad"),
+                (X_B3_SPAN_ID_HEADER, "a2fb4a1d1a96d3"),
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_hex_trace_id() {
+        assert_eq!(
+            hex_trace_id("0af7651916cd43dd8448eb211c80319c"),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(
+            hex_trace_id("4C7B5E0A-3F6D-4B2E-9C1A-7D8E9F0A1B2C"),
+            "4c7b5e0a3f6d4b2e9c1a7d8e9f0a1b2c"
+        );
+        assert_eq!(
+            hex_trace_id("abc.123.def"),
+            "00000000000000000000000abc123def"
+        );
+        assert_eq!(
+            hex_trace_id("1234567890abcdef1234567890abcdef12"),
+            "34567890abcdef1234567890abcdef12"
+        );
+    }
+
+    #[test]
+    fn test_correlation_decode() {
+        let ctx =
+            CorrelationContext::decode_with_limits("dGVzdA==:dmFsdWU=,,invalid,a2V5:", 3, 128);
+        assert_eq!(ctx.get("test"), Some("value"));
+        assert_eq!(ctx.get("key"), Some(""));
+        assert_eq!(ctx.get("invalid"), None);
+        assert_eq!(ctx.encode().as_deref(), Some("dGVzdA==:dmFsdWU=,a2V5:"));
+
+        let ctx = CorrelationContext::decode_with_limits("YQ==:MQ==,Yg==:Mg==,Yw==:Mw==", 2, 128);
+        assert_eq!(ctx.get("a"), Some("1"));
+        assert_eq!(ctx.get("b"), Some("2"));
+        assert_eq!(ctx.get("c"), None);
+
+        assert!(CorrelationContext::decode_with_limits("", 3, 128)
+            .encode()
+            .is_none());
+    }
+
+    #[test]
+    fn test_correlation_put() {
+        let mut ctx = CorrelationContext::default();
+        assert!(ctx.put_with_limits("a".to_owned(), "1".to_owned(), 2, 3));
+        assert!(ctx.put_with_limits("b".to_owned(), "123".to_owned(), 2, 3));
+        assert!(!ctx.put_with_limits("c".to_owned(), "1".to_owned(), 2, 3));
+        assert!(ctx.put_with_limits("a".to_owned(), "2".to_owned(), 2, 3));
+        assert!(!ctx.put_with_limits("a".to_owned(), "1234".to_owned(), 2, 3));
+        assert!(!ctx.put_with_limits("".to_owned(), "1".to_owned(), 2, 3));
+        assert_eq!(ctx.get("a"), Some("2"));
+        assert_eq!(ctx.get("b"), Some("123"));
+        assert_eq!(ctx.get("c"), None);
+    }
+}
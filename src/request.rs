@@ -9,12 +9,21 @@
 // See the Mulan PSL v2 for more details.
 
 use crate::{
+    api::finish_local_spans,
+    capture::{add_params_tag, add_request_header_tags},
+    cds::{self, is_ignored_path, try_sample},
+    client_ip::resolve_client_ip,
     component::COMPONENT_PHP_ID,
     context::RequestContext,
-    module::is_ready_for_request,
+    meter::record_request,
+    module::{is_ready_for_request, IS_CLI},
+    plugin::clear_plugins,
+    propagation::{extract_propagation, is_propagated, ExtractedContext},
+    redact::{redact_url, RedactedTag},
     util::{catch_unwind_anyhow, z_val_to_string},
 };
 use anyhow::Context;
+use chrono::Utc;
 use phper::{
     arrays::ZArr,
     eg,
@@ -22,15 +31,28 @@ use phper::{
     pg, sg,
     sys::{self},
 };
-use skywalking::context::{
-    propagation::decoder::decode_propagation,
-    tracer::{self},
-};
-use tracing::{error, instrument, trace, warn};
+use std::cell::Cell;
+use tracing::{error, instrument, warn};
+
+thread_local! {
+    /// The request isn't traced, because of the ignored path or sampling of the
+    /// dynamic configuration.
+    static IS_SKIPPED: Cell<bool> = Cell::new(false);
+
+    /// The start time of the request in milliseconds, the request meter records
+    /// both the traced and the skipped requests.
+    static START_TIME: Cell<i64> = Cell::new(0);
+}
+
+/// Whether the current request isn't traced, so the plugins needn't hook.
+pub fn is_request_skipped() -> bool {
+    IS_SKIPPED.with(Cell::get)
+}
 
 #[instrument(skip_all)]
 pub fn init(_module: ModuleContext) -> bool {
-    if is_ready_for_request() {
+    // In cli mode, the trace context is created by plugins, such as mq consumers.
+    if is_ready_for_request() && !*IS_CLI {
         if let Err(err) = catch_unwind_anyhow(|| request_init(None)) {
             error!(?err, "request init failed");
         }
@@ -41,6 +63,8 @@ pub fn init(_module: ModuleContext) -> bool {
 #[instrument(skip_all)]
 pub fn shutdown(_module: ModuleContext) -> bool {
     if is_ready_for_request() {
+        // The spans kept by plugins are finished before the entry span.
+        clear_plugins();
         if let Err(err) = catch_unwind_anyhow(|| request_shutdown(None)) {
             error!(?err, "request shutdown failed");
         }
@@ -49,42 +73,63 @@ pub fn shutdown(_module: ModuleContext) -> bool {
 }
 
 fn request_init(request_id: Option<u64>) -> anyhow::Result<()> {
+    START_TIME.with(|start_time| start_time.set(Utc::now().timestamp_millis()));
     jit_initialization();
+    cds::refresh();
 
     let server = get_page_request_server()?;
 
-    let header = get_page_request_header(server);
     let uri = get_page_request_uri(server);
     let method = get_page_request_method(server);
 
-    let propagation = header
-        .as_ref()
-        .and_then(|header| match decode_propagation(&header) {
-            Ok(propagation) => Some(propagation),
-            Err(e) => {
-                error!("Decode propagation failed: {}", e);
-                None
-            }
-        });
-
-    trace!("Propagation: {:?}", &propagation);
-
-    let mut ctx = match propagation {
-        Some(propagation) => tracer::create_trace_context_from_propagation(propagation),
-        None => tracer::create_trace_context(),
-    };
-
-    let operation_name = format!("{method}:{uri}");
-    let mut span = ctx.create_entry_span(&operation_name);
+    // The propagated traces are always kept, so the trace isn't broken.
+    let is_propagated = is_propagated(|name| get_page_request_header(server, name));
+    let path = uri.split('?').next().unwrap_or_default();
+    if is_ignored_path(path) || (!is_propagated && !try_sample()) {
+        IS_SKIPPED.with(|is_skipped| is_skipped.set(true));
+        return Ok(());
+    }
+
+    let mut extracted = extract_propagation(|name| get_page_request_header(server, name));
+
+    let operation_name = format!("{method}:{}", redact_url(&uri));
+    let mut span = extracted.create_entry_span(&operation_name);
     span.with_span_object_mut(|span| span.component_id = COMPONENT_PHP_ID);
-    span.add_tag("url", &uri);
+    span.add_redacted_tag("url", &uri);
     span.add_tag("http.method", &method);
+    add_request_header_tags(&mut span, |name| get_page_request_header(server, name));
+
+    let remote_addr = server.get("REMOTE_ADDR").and_then(z_val_to_string);
+    let client_ip =
+        resolve_client_ip(remote_addr.as_deref(), |name| get_page_request_header(server, name));
+    if let Some(client_ip) = client_ip {
+        let client_ip = client_ip.to_string();
+        span.add_redacted_tag("client.ip", &client_ip);
+        span.with_span_object_mut(|span| span.peer = client_ip);
+    }
+    let (get, post) = (get_page_request_global("_GET"), get_page_request_global("_POST"));
+    add_params_tag(&mut span, &[get, post]);
+
+    let ExtractedContext {
+        tracing_context: ctx,
+        correlation,
+        extension,
+        trace_state,
+        sampled,
+        ..
+    } = extracted;
 
     RequestContext::set_global(
         request_id,
         RequestContext {
             tracing_context: ctx,
             entry_span: span,
+            correlation,
+            extension,
+            trace_state,
+            sampled,
+            log_count: 0,
+            span_count: 0,
         },
     );
 
@@ -92,9 +137,31 @@ fn request_init(request_id: Option<u64>) -> anyhow::Result<()> {
 }
 
 fn request_shutdown(request_id: Option<u64>) -> anyhow::Result<()> {
+    if IS_SKIPPED.with(|is_skipped| is_skipped.replace(false)) {
+        record_request_meter();
+        return Ok(());
+    }
+
+    finish_local_spans();
+
+    if *IS_CLI {
+        // Finish the trace context left by plugins.
+        if let Some(RequestContext {
+            tracing_context,
+            entry_span,
+            ..
+        }) = RequestContext::remove_global(request_id)
+        {
+            drop(entry_span);
+            drop(tracing_context);
+        }
+        return Ok(());
+    }
+
     let RequestContext {
         tracing_context,
         mut entry_span,
+        ..
     } = RequestContext::remove_global(request_id).context("request context not exists")?;
 
     let status_code = unsafe { sg!(sapi_headers).http_response_code };
@@ -106,9 +173,19 @@ fn request_shutdown(request_id: Option<u64>) -> anyhow::Result<()> {
     drop(entry_span);
     drop(tracing_context);
 
+    record_request_meter();
+
     Ok(())
 }
 
+fn record_request_meter() {
+    let start_time = START_TIME.with(Cell::get);
+    let duration = (Utc::now().timestamp_millis() - start_time).max(0) as u64;
+    if let Err(err) = record_request(duration) {
+        warn!(?err, "record request meter failed");
+    }
+}
+
 fn jit_initialization() {
     unsafe {
         let jit_initialization = pg!(auto_globals_jit);
@@ -119,13 +196,12 @@ fn jit_initialization() {
     }
 }
 
-fn get_page_request_header(server: &ZArr) -> Option<String> {
+/// Get the request header by the lowercase name, like `sw8-correlation` from
+/// `$_SERVER['HTTP_SW8_CORRELATION']`.
+fn get_page_request_header(server: &ZArr, name: &str) -> Option<String> {
     // TODO Support multi skywlaking version.
-    server
-        .get("HTTP_SW8")
-        .and_then(|sw| sw.as_z_str())
-        .and_then(|zs| zs.to_str().ok())
-        .map(|s| s.to_string())
+    let key = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+    server.get(&*key).and_then(z_val_to_string)
 }
 
 fn get_page_request_uri(server: &ZArr) -> String {
@@ -145,12 +221,13 @@ fn get_page_request_method(server: &ZArr) -> String {
 }
 
 fn get_page_request_server<'a>() -> anyhow::Result<&'a ZArr> {
+    get_page_request_global("_SERVER").context("$_SERVER is null")
+}
+
+/// Get the super global array, like `$_GET`.
+fn get_page_request_global<'a>(name: &str) -> Option<&'a ZArr> {
     unsafe {
         let symbol_table = ZArr::from_mut_ptr(&mut eg!(symbol_table));
-        let carrier = symbol_table
-            .get("_SERVER")
-            .and_then(|carrier| carrier.as_z_arr())
-            .context("$_SERVER is null")?;
-        Ok(carrier)
+        symbol_table.get(name).and_then(|carrier| carrier.as_z_arr())
     }
 }
@@ -11,7 +11,10 @@
 use anyhow::bail;
 use chrono::Local;
 use once_cell::sync::Lazy;
-use phper::values::ZVal;
+use phper::{
+    arrays::{InsertKey, IterKey, ZArr, ZArray},
+    values::ZVal,
+};
 use std::panic::{catch_unwind, UnwindSafe};
 use systemstat::{IpAddr, Platform, System};
 
@@ -52,8 +55,6 @@ pub static IPS: Lazy<Vec<String>> = Lazy::new(|| {
         .unwrap_or_else(|| vec!["127.0.0.1".to_owned()])
 });
 
-// TODO Maybe report_instance_properties used.
-#[allow(dead_code)]
 pub static HOST_NAME: Lazy<String> = Lazy::new(|| {
     hostname::get()
         .ok()
@@ -85,6 +86,19 @@ pub fn z_val_to_string(zv: &ZVal) -> Option<String> {
         .map(|s| s.to_string())
 }
 
+/// Copy the array to modify, rather than modify it directly, because the array
+/// may be shared with the caller.
+pub fn copy_array(arr: &ZArr) -> ZArray {
+    let mut new_arr = ZArray::new();
+    for (key, value) in arr.iter() {
+        match key {
+            IterKey::Index(i) => new_arr.insert(InsertKey::Key(i), value.clone()),
+            IterKey::ZStr(s) => new_arr.insert(InsertKey::ZStr(s), value.clone()),
+        }
+    }
+    new_arr
+}
+
 pub fn catch_unwind_anyhow<F: FnOnce() -> anyhow::Result<R> + UnwindSafe, R>(
     f: F,
 ) -> anyhow::Result<R> {
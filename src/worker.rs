@@ -9,25 +9,41 @@
 // See the Mulan PSL v2 for more details.
 
 use crate::{
-    channel::{self},
-    module::{mark_ready_for_request, SERVICE_INSTANCE, SERVICE_NAME},
-    SKYWALKING_AGENT_SERVER_ADDR, SKYWALKING_AGENT_WORKER_THREADS,
+    cds::{fetch_configurations, CDS_ENABLE},
+    channel::{self, release_count, MessageKind},
+    meter::{MeterAggregator, RuntimeMetrics, REPORT_INTERVAL},
+    module::{mark_ready_for_request, server_addr, SERVICE_INSTANCE, SERVICE_NAME},
+    SKYWALKING_AGENT_WORKER_THREADS,
 };
 use libc::{fork, prctl, PR_SET_PDEATHSIG, SIGTERM};
 use phper::ini::Ini;
-use skywalking::{context::tracer::Tracer, reporter::grpc::GrpcReporter};
+use skywalking::{
+    context::tracer::Tracer,
+    reporter::grpc::GrpcReporter,
+    skywalking_proto::v3::{
+        log_report_service_client::LogReportServiceClient,
+        meter_report_service_client::MeterReportServiceClient, LogData,
+    },
+};
 use std::{
-    future, num::NonZeroUsize, process::exit, thread::available_parallelism, time::Duration,
+    collections::HashMap, future, num::NonZeroUsize, process::exit, thread::available_parallelism,
+    time::Duration,
 };
 use tokio::{
     runtime::{self, Runtime},
-    time::sleep,
+    select,
+    sync::mpsc,
+    task,
+    time::{interval, sleep, MissedTickBehavior},
 };
 use tonic::transport::{Channel, Endpoint};
 use tracing::{debug, error, info, warn};
 
+/// Max count of the logs reported in one stream.
+const MAX_LOG_BATCH_SIZE: usize = 100;
+
 pub fn init_worker() {
-    let server_addr = Ini::get::<String>(SKYWALKING_AGENT_SERVER_ADDR).unwrap_or_default();
+    let server_addr = server_addr();
     let worker_threads = worker_threads();
     let service_name = SERVICE_NAME.to_string();
     let service_instance = SERVICE_INSTANCE.to_string();
@@ -74,11 +90,21 @@ async fn start_worker(server_addr: String, service_name: String, service_instanc
     };
     let channel = connect(endpoint).await;
 
+    let (segment_sender, segment_receiver) = mpsc::channel(MessageKind::Segment.max_count());
+    let (log_sender, log_receiver) = mpsc::channel(MessageKind::Log.max_count());
+    let (meter_sender, meter_receiver) = mpsc::channel(MessageKind::Meter.max_count());
+    task::spawn_blocking(move || channel::dispatch(segment_sender, log_sender, meter_sender));
+    task::spawn(report_logs(channel.clone(), log_receiver));
+    task::spawn(report_meters(channel.clone(), meter_receiver));
+    if *CDS_ENABLE {
+        task::spawn(fetch_configurations(channel.clone()));
+    }
+
     let tracer = Tracer::new_with_channel(
         service_name,
         service_instance,
         GrpcReporter::new(channel),
-        ((), channel::Receiver),
+        ((), channel::Receiver::new(segment_receiver)),
     );
 
     // report_instance_properties(channel.clone()).await;
@@ -93,6 +119,69 @@ async fn start_worker(server_addr: String, service_name: String, service_instanc
     // handle.await;
 }
 
+/// Report the logs by `LogReportService/collect`, the logs received at the
+/// same time are reported in one stream.
+#[tracing::instrument(skip_all)]
+async fn report_logs(channel: Channel, mut log_receiver: mpsc::Receiver<LogData>) {
+    let mut client = LogReportServiceClient::new(channel);
+
+    while let Some(log) = log_receiver.recv().await {
+        let mut logs = vec![log];
+        while logs.len() < MAX_LOG_BATCH_SIZE {
+            match log_receiver.try_recv() {
+                Ok(log) => logs.push(log),
+                Err(_) => break,
+            }
+        }
+
+        let count = logs.len();
+        match client.collect(tokio_stream::iter(logs)).await {
+            Ok(_) => debug!(count, "Report logs"),
+            Err(err) => warn!(?err, count, "Report logs failed"),
+        }
+        release_count(MessageKind::Log, count);
+    }
+}
+
+/// Aggregate the runtime metrics of php processes by service, and report them
+/// by `MeterReportService/collect` periodically.
+#[tracing::instrument(skip_all)]
+async fn report_meters(
+    channel: Channel, mut meter_receiver: mpsc::Receiver<RuntimeMetrics>,
+) {
+    let mut client = MeterReportServiceClient::new(channel);
+    let mut aggregators: HashMap<String, MeterAggregator> = HashMap::new();
+
+    let mut ticker = interval(*REPORT_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        select! {
+            metrics = meter_receiver.recv() => match metrics {
+                Some(metrics) => {
+                    aggregators.entry(metrics.service().to_owned()).or_default().merge(metrics);
+                    release_count(MessageKind::Meter, 1);
+                }
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if aggregators.is_empty() {
+                    continue;
+                }
+                let meters = aggregators
+                    .iter_mut()
+                    .flat_map(|(service, aggregator)| aggregator.take_meter_data(service))
+                    .collect::<Vec<_>>();
+                let count = meters.len();
+                match client.collect(tokio_stream::iter(meters)).await {
+                    Ok(_) => debug!(count, "Report meters"),
+                    Err(err) => warn!(?err, count, "Report meters failed"),
+                }
+            }
+        }
+    }
+}
+
 #[tracing::instrument(skip_all)]
 async fn connect(endpoint: Endpoint) -> Channel {
     let channel = loop {